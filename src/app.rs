@@ -1,13 +1,53 @@
 /// Clap App Constructor
 use clap::{crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
+use time::DuplicityTime;
 
-/// Construct the repository argument that is used in all subcommands.
-fn repository_arg<'a, 'b>() -> Arg<'a, 'b> {
-    Arg::with_name("repository")
-        .required(true)
-        .takes_value(true)
-        .help("Repository to backup")
-        .long_help("Repository to backup, as set in the configuration file.")
+/// Check that the argument is a time in one of the formats accepted by
+/// duplicity.
+#[allow(clippy::needless_pass_by_value)]
+fn validate_time(s: String) -> Result<(), String> {
+    s.parse::<DuplicityTime>()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Construct the arguments selecting repositories that are used in all
+/// subcommands.
+fn repository_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("repository")
+            .required_unless_one(&["tag", "all"])
+            .takes_value(true)
+            .multiple(true)
+            .help("Repositories to run")
+            .long_help(
+                "\
+Repositories to run, as set in the configuration file.  Glob patterns such as \
+'foo:*' select all the matching repositories.  Groups are expanded into the \
+repositories they list, and each repository is only run once, in the order in \
+which it is first selected.",
+            ),
+        Arg::with_name("tag")
+            .long("tag")
+            .value_name("TAG")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Select the repositories with the given tag")
+            .long_help(
+                "\
+Select the repositories which list the given tag within 'tags', after those \
+given by name.  This can be specified multiple times.",
+            ),
+        Arg::with_name("all")
+            .long("all")
+            .help("Select all repositories")
+            .long_help(
+                "\
+Select all repositories, after those given by name or tag, in order of their \
+name.",
+            ),
+    ]
 }
 
 /// Backup subcommand
@@ -26,10 +66,14 @@ avoid timeouts.
 If the repository has any of the 'remove-older-than', 'remove-all-but-n-full' or \
 'remove-all-inc-of-but-n-full' options, successful completion of the backup \
 (whether full or incremental) will automatically be followed by the appropriate \
-commands above.",
+commands above.
+
+If the repository has the 'auto_cleanup' option, the backup is followed by \
+'cleanup --force' after a failure (including timeouts) or always, as specified.  \
+The outcome of the cleanup is reported separately from that of the backup.",
         )
         .display_order(1)
-        .arg(repository_arg())
+        .args(&repository_args())
 }
 
 /// Verify subcommand
@@ -42,7 +86,7 @@ Verify the backup against the original files.  See duplicity's manual for more \
 information about the options below.
 ",
         )
-        .arg(repository_arg())
+        .args(&repository_args())
         .arg(
             Arg::with_name("compare-data")
                 .long("compare-data")
@@ -53,6 +97,7 @@ information about the options below.
                 .long("time")
                 .takes_value(true)
                 .value_name("TIME")
+                .validator(validate_time)
                 .multiple(false)
                 .help(
                     "\
@@ -82,7 +127,7 @@ Summarize the status of the backup repository by printing the chains and sets \
 found, and the number of volumes in each.
 ",
         )
-        .arg(repository_arg())
+        .args(&repository_args())
         .arg(
             Arg::with_name("file-changed")
                 .long("file-changed")
@@ -108,7 +153,7 @@ fn cleanup<'a, 'b>() -> App<'a, 'b> {
              necessary after a duplicity sessions fails or is aborted.  Note that '--force' \
              is required to actually delete the files instead of just listing them.",
         )
-        .arg(repository_arg())
+        .args(&repository_args())
         .arg(Arg::with_name("force").long("force").help(
             "\
              Delete the files instead of just listing them (refer to duplicity manual).",
@@ -125,12 +170,13 @@ fn list_current_files<'a, 'b>() -> App<'a, 'b> {
     SubCommand::with_name("list-current-files")
         .about("List the files contained in the backup")
         .long_about("List the files contained in the backup.")
-        .arg(repository_arg())
+        .args(&repository_args())
         .arg(
             Arg::with_name("time")
                 .long("time")
                 .takes_value(true)
                 .value_name("TIME")
+                .validator(validate_time)
                 .multiple(false)
                 .help(
                     "\
@@ -140,6 +186,230 @@ fn list_current_files<'a, 'b>() -> App<'a, 'b> {
         )
 }
 
+/// List subcommand
+fn list<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("list")
+        .about("List the configured repositories")
+        .long_about(
+            "\
+List the configured repositories along with their kind (leaf or group).  For \
+leaves, the source, remote (with any password redacted), tags and retention \
+options are shown.  For groups, the tree of sub-repositories is shown.",
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Output format"),
+        )
+        .arg(Arg::with_name("all-hosts").long("all-hosts").help(
+            "\
+                 Include the repositories hidden by their 'hosts' or 'users', marked as hidden.",
+        ))
+}
+
+/// Keys subcommand
+fn keys<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("keys")
+        .about("Manage the GPG keys used by the repositories")
+        .long_about("Manage the GPG keys used by the repositories.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check the GPG keys used by the repositories")
+                .long_about(
+                    "\
+Look up each of the keys given by 'encrypt_key', 'hidden_encrypt_key', \
+'sign_key' and 'encrypt_sign_key' with the local gpg, using the repository's \
+'gpg_binary', 'gpg_options' and 'encrypt_secret_keyring'.  For each key, its \
+fingerprint and expiry are shown, along with whether the secret key needed to \
+restore or verify the backup is available.
+
+A key which is missing, revoked or expired, or a signing key without its secret \
+key, is an error.  A key which expires within 'key_expiry_warning_days' (default: \
+30) is a warning.  The same checks are run before each backup, which fails on any \
+error before anything is uploaded.  The exit code is 1 if any error is found.",
+                )
+                .args(&repository_args()),
+        )
+}
+
+/// Disaster-recovery bundle subcommand
+fn dr_bundle<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("dr-bundle")
+        .about("Export what is needed to restore the backups on another machine")
+        .long_about(
+            "\
+Write a disaster-recovery bundle for the specified repositories: a tar archive \
+encrypted with gpg holding everything needed to restore their backups should \
+this machine be lost.  The bundle contains:
+
+  - the configuration of the repositories, fully resolved;
+  - the environment set when running duplicity (passphrases and credentials);
+  - the public and secret keys, with '--export-public-keys' and \
+'--export-secret-keys';
+  - the version of duplicity;
+  - a README with the exact commands to restore each repository.
+
+The bundle is encrypted to the keys given with '--recipient', or with a \
+passphrase prompted by gpg if there are none.  It is never written to disk \
+unencrypted.  With '--dry-run', the files which would be included are listed \
+instead.
+
+Use 'dr-bundle verify' to check that a bundle can be decrypted and is complete.",
+        )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&repository_args())
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("File to which the bundle is written, required unless '--dry-run' is given"),
+        )
+        .arg(
+            Arg::with_name("recipient")
+                .short("r")
+                .long("recipient")
+                .takes_value(true)
+                .value_name("KEY")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Encrypt the bundle to the given key instead of a passphrase")
+                .long_help(
+                    "\
+Encrypt the bundle to the given key instead of a passphrase.  This can be \
+specified multiple times.  The secret key must be kept somewhere other than this \
+machine for the bundle to be of any use.",
+                ),
+        )
+        .arg(
+            Arg::with_name("export-public-keys")
+                .long("export-public-keys")
+                .help("Include the public keys of the repositories"),
+        )
+        .arg(
+            Arg::with_name("export-secret-keys")
+                .long("export-secret-keys")
+                .help("Include the secret keys of the repositories")
+                .long_help(
+                    "\
+Include the secret keys of the repositories, as exported by gpg (which may \
+prompt for their passphrases).  Without them, the backups can only be restored \
+with a copy of the secret keys kept elsewhere.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that a disaster-recovery bundle is complete")
+                .long_about(
+                    "\
+Decrypt the bundle and check that it holds every file listed in its manifest, \
+that the configuration holds valid options for each repository, and that the \
+key exports are key blocks.  The configuration file is not needed.",
+                )
+                .arg(
+                    Arg::with_name("bundle")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Bundle to verify"),
+                ),
+        )
+}
+
+/// Config subcommand
+fn config<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("config")
+        .about("Manage the configuration file")
+        .long_about("Manage the configuration file.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Migrate the configuration file to the latest format")
+                .long_about(
+                    "\
+Migrate the configuration file to the latest format.  Unless a file is given \
+with '--config', the configuration file with the highest precedence is migrated.  \
+The original file is kept alongside the new one with a '.bak' extension.  Note \
+that comments within the configuration file are not preserved.
+
+With '--dry-run', the migrated configuration is printed instead of written.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("paths")
+                .about("Show which configuration files are loaded")
+                .long_about(
+                    "\
+Show the locations searched for configuration files and whether they were found, \
+all the files which were loaded (including those included by others), and the \
+file in which each repository is defined.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Look for likely mistakes within the configuration")
+                .long_about(
+                    "\
+Look for repository layouts which are valid but likely to be mistakes:
+
+  shared-remote (error): two repositories use the same remote
+  remote-inside-source (error): a file:// remote is within its own source
+  retention-keeps-nothing (error): the retention options remove every backup
+  overlapping-sources (warning): sources overlap without distinct names
+  unencrypted-remote (warning): 'no_encryption' is set for a non-local remote
+  unreachable-exclude (warning): an 'exclude' is already matched by an 'include'
+  home-relative-remote (warning): an ssh remote path looks absolute but is not
+
+A lint can be suppressed for a repository by listing it within the repository's \
+'allow_lints'.  The exit code is 1 if any error is found.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check that the configuration is valid")
+                .long_about(
+                    "\
+Load the configuration and run all the checks on it, without running anything \
+else.  This includes checking that the paths of the repositories which apply to \
+this host exist, and running the lints of 'config lint' on them.  The exit code is 0 if the configuration is valid and 1 otherwise, so that \
+this can be used in scripts and pre-commit hooks.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Show the fully resolved options of a repository")
+                .long_about(
+                    "\
+Show the options of a repository after the defaults and any templates it extends \
+have been applied, and its variables and paths expanded.  This is followed by the \
+values as written for those which were expanded, and the exact command run by \
+duplicity-front to back up the repository.
+
+Secrets (the passphrases, the variables of 'env', the credentials of the backends \
+and the password within the remote) are shown as '***' unless --show-secrets is \
+given.",
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Repository to show"),
+                )
+                .arg(
+                    Arg::with_name("show-secrets")
+                        .long("show-secrets")
+                        .takes_value(false)
+                        .help("Show the secrets instead of hiding them"),
+                ),
+        )
+}
+
 /// Final construct for everything
 pub fn app<'a, 'b>() -> App<'a, 'b> {
     App::new("duplicity-front")
@@ -183,13 +453,40 @@ Project home page: https://github.com/JP-Ellis/duplicity-front",
                 .global(true)
                 .value_name("FILE")
                 .takes_value(true)
-                .default_value("~/.config/duplicity-front.yml")
+                .env("DUPLICITY_FRONT_CONFIG")
                 .number_of_values(1)
                 .help("Configuration file name")
                 .long_help(
                     "\
 Specify the configuration file name containing information about backup \
-repositories.",
+repositories.  Only this file (and the files it includes) is loaded.
+
+If not specified, the following files are loaded if they exist, with later files \
+taking precedence over earlier ones:
+
+  1. /etc/duplicity-front.yml
+  2. duplicity-front.yml within each of $XDG_CONFIG_DIRS (default: /etc/xdg), \
+with earlier directories taking precedence
+  3. duplicity-front.yml within $XDG_CONFIG_HOME (default: ~/.config)
+
+Defaults are merged, with later files taking precedence.  A repository or \
+template defined in more than one file must set 'override: true' on the later \
+definition.  Use 'config paths' to see which files were loaded.",
+                ),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .global(true)
+                .value_name("HOST")
+                .takes_value(true)
+                .number_of_values(1)
+                .help("Host name used to select repositories")
+                .long_help(
+                    "\
+Host name against which the 'hosts' of each repository are matched, instead of \
+the host name of this machine.  It is also used for the '${hostname}' variable.  \
+This is useful to check the configuration as seen by another machine.",
                 ),
         )
         .arg(
@@ -219,9 +516,55 @@ multiple times for increasing levels of verbosity.",
 Perform a run and calculate what will be changed, but take no action.",
                 ),
         )
+        .arg(
+            Arg::with_name("max-runtime")
+                .long("max-runtime")
+                .global(true)
+                .value_name("SECONDS")
+                .takes_value(true)
+                .number_of_values(1)
+                .help("Maximum runtime of the whole run")
+                .long_help(
+                    "\
+Maximum runtime in seconds of the whole run.  Once exceeded, the running \
+duplicity process is terminated and no further commands are run.  Each \
+repository may additionally specify its own 'max_runtime', which applies to \
+each duplicity command run for that repository.",
+                ),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .long("wait")
+                .global(true)
+                .takes_value(false)
+                .overrides_with("no-wait")
+                .help("Wait for repositories locked by another process")
+                .long_help(
+                    "\
+Wait for repositories which are locked by another instance of duplicity-front \
+instead of failing immediately.",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-wait")
+                .long("no-wait")
+                .global(true)
+                .takes_value(false)
+                .overrides_with("wait")
+                .help("Fail if a repository is locked by another process (default)")
+                .long_help(
+                    "\
+Fail immediately if a repository is locked by another instance of \
+duplicity-front.  This is the default.  In this case, the exit code is 75.",
+                ),
+        )
         .subcommand(backup())
         .subcommand(verify())
         .subcommand(collection_status())
         .subcommand(list_current_files())
         .subcommand(cleanup())
+        .subcommand(list())
+        .subcommand(keys())
+        .subcommand(dr_bundle())
+        .subcommand(config())
 }
@@ -0,0 +1,181 @@
+//! Reading and writing of tar archives in the ustar format.
+//!
+//! Only what is needed for the disaster-recovery bundles is supported: the
+//! archives are built in memory from regular files (and their directories),
+//! so that their contents are never written to disk unencrypted.
+
+use error::Error;
+
+/// Size of the blocks making up an archive.
+const BLOCK: usize = 512;
+
+/// A file within an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    pub fn new<S, D>(path: S, data: D) -> Self
+    where
+        S: Into<String>,
+        D: Into<Vec<u8>>,
+    {
+        Entry {
+            path: path.into(),
+            data: data.into(),
+        }
+    }
+}
+
+/// Write `value` as a NUL-terminated octal number filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// Read the octal number within `field`.
+fn read_octal(field: &[u8]) -> Option<u64> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        Some(0)
+    } else {
+        u64::from_str_radix(digits, 8).ok()
+    }
+}
+
+/// Read the NUL-terminated string within `field`.
+fn read_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Compute the checksum of the header, taking its checksum field as spaces.
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum()
+}
+
+/// Construct the header of an entry, splitting long paths between the name
+/// and prefix fields.
+fn header(path: &str, size: u64, mtime: i64, directory: bool) -> Result<[u8; BLOCK], Error> {
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        path.char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && path.len() - i - 1 <= 100)
+            .map(|(i, _)| (&path[..i], &path[i + 1..]))
+            .next()
+            .ok_or_else(|| Error::new(format!("The path '{}' is too long to archive.", path)))?
+    };
+
+    let mut header = [0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], if directory { 0o700 } else { 0o600 });
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = if directory { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    let sum = format!("{:06o}\0 ", checksum(&header));
+    header[148..156].copy_from_slice(sum.as_bytes());
+    Ok(header)
+}
+
+/// Write the entries into an archive, preceding each with the directories
+/// leading to it.
+pub fn write(entries: &[Entry], mtime: i64) -> Result<Vec<u8>, Error> {
+    let mut archive = Vec::new();
+    let mut directories: Vec<&str> = Vec::new();
+    for entry in entries {
+        for (i, _) in entry.path.match_indices('/') {
+            let directory = &entry.path[..i];
+            if !directories.contains(&directory) {
+                archive.extend_from_slice(&header(directory, 0, mtime, true)?);
+                directories.push(directory);
+            }
+        }
+
+        archive.extend_from_slice(&header(&entry.path, entry.data.len() as u64, mtime, false)?);
+        archive.extend_from_slice(&entry.data);
+        let padding = (BLOCK - entry.data.len() % BLOCK) % BLOCK;
+        archive.extend(::std::iter::repeat_n(0, padding));
+    }
+    archive.extend_from_slice(&[0; 2 * BLOCK]);
+    Ok(archive)
+}
+
+/// Read the regular files within an archive.
+pub fn read(archive: &[u8]) -> Result<Vec<Entry>, Error> {
+    let truncated = || Error::new("The archive is truncated.");
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = archive.get(offset..offset + BLOCK).ok_or_else(truncated)?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(entries);
+        }
+        if read_octal(&header[148..156]) != Some(checksum(header)) {
+            return Err(Error::new(format!(
+                "The header at offset {} of the archive is corrupt.",
+                offset
+            )));
+        }
+
+        let name = read_string(&header[..100]);
+        let prefix = read_string(&header[345..500]);
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let size = read_octal(&header[124..136])
+            .ok_or_else(|| Error::new(format!("The size of '{}' is corrupt.", path)))?
+            as usize;
+
+        offset += BLOCK;
+        let data = archive.get(offset..offset + size).ok_or_else(truncated)?;
+        if header[156] == b'0' || header[156] == 0 {
+            entries.push(Entry::new(path, data));
+        }
+        offset += size.div_ceil(BLOCK) * BLOCK;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read, write, Entry};
+
+    #[test]
+    fn round_trip() {
+        let long = format!("bundle/{}/{}/file.txt", "d".repeat(60), "e".repeat(60));
+        let entries = vec![
+            Entry::new("bundle/README.md", "# Read me\n"),
+            Entry::new("bundle/keys/empty.asc", ""),
+            Entry::new(long, vec![7; 1500]),
+        ];
+        let archive = write(&entries, 1_600_000_000).unwrap();
+        assert_eq!(archive.len() % 512, 0);
+        assert_eq!(read(&archive).unwrap(), entries);
+
+        let mut corrupt = archive.clone();
+        corrupt[0] = b'X';
+        assert!(read(&corrupt).is_err());
+        assert!(read(&archive[..1024]).is_err());
+    }
+}
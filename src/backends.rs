@@ -0,0 +1,216 @@
+//! Options specific to a backend.
+//!
+//! Most of duplicity's backends read their credentials from environment
+//! variables instead of flags.  Each block maps its options onto the
+//! environment variables and flags understood by the corresponding backend,
+//! so that the credentials need not be exported before running the front.
+
+/// A flag given to duplicity, along with its arguments.
+pub type Flag = (&'static str, Vec<String>);
+
+/// Storage class of the objects uploaded to S3.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum S3StorageClass {
+    Standard,
+    StandardIa,
+    OnezoneIa,
+    ReducedRedundancy,
+    Glacier,
+    DeepArchive,
+}
+
+impl S3StorageClass {
+    fn flag(self) -> Option<&'static str> {
+        match self {
+            S3StorageClass::Standard => None,
+            S3StorageClass::StandardIa => Some("--s3-use-ia"),
+            S3StorageClass::OnezoneIa => Some("--s3-use-onezone-ia"),
+            S3StorageClass::ReducedRedundancy => Some("--s3-use-rrs"),
+            S3StorageClass::Glacier => Some("--s3-use-glacier"),
+            S3StorageClass::DeepArchive => Some("--s3-use-deep-archive"),
+        }
+    }
+}
+
+/// Options of the `s3://` backend.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct S3Options {
+    /// Set as `AWS_ACCESS_KEY_ID`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_key: Option<String>,
+    /// Set as `AWS_SECRET_ACCESS_KEY`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_class: Option<S3StorageClass>,
+}
+
+impl S3Options {
+    pub fn environment(&self) -> Vec<(String, String)> {
+        environment(&[
+            ("AWS_ACCESS_KEY_ID", &self.access_key),
+            ("AWS_SECRET_ACCESS_KEY", &self.secret_key),
+        ])
+    }
+
+    pub fn flags(&self) -> Vec<Flag> {
+        let mut flags: Vec<Flag> = Vec::new();
+        if let Some(ref arg) = self.region {
+            flags.push(("--s3-region-name", vec![arg.to_string()]));
+        }
+        if let Some(ref arg) = self.endpoint_url {
+            flags.push(("--s3-endpoint-url", vec![arg.to_string()]));
+        }
+        if let Some(flag) = self.storage_class.and_then(S3StorageClass::flag) {
+            flags.push((flag, Vec::new()));
+        }
+        flags
+    }
+}
+
+/// Options of the `gs://` backend.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct GsOptions {
+    /// Set as `GS_ACCESS_KEY_ID`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_key: Option<String>,
+    /// Set as `GS_SECRET_ACCESS_KEY`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>,
+}
+
+impl GsOptions {
+    pub fn environment(&self) -> Vec<(String, String)> {
+        environment(&[
+            ("GS_ACCESS_KEY_ID", &self.access_key),
+            ("GS_SECRET_ACCESS_KEY", &self.secret_key),
+        ])
+    }
+}
+
+/// Access tier of the blobs uploaded to Azure.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AzureBlobTier {
+    Hot,
+    Cool,
+    Archive,
+}
+
+impl AzureBlobTier {
+    fn as_arg(self) -> &'static str {
+        match self {
+            AzureBlobTier::Hot => "Hot",
+            AzureBlobTier::Cool => "Cool",
+            AzureBlobTier::Archive => "Archive",
+        }
+    }
+}
+
+/// Options of the `azure://` backend.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct AzureOptions {
+    /// Set as `AZURE_ACCOUNT_NAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_name: Option<String>,
+    /// Set as `AZURE_ACCOUNT_KEY`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_key: Option<String>,
+    /// Set as `AZURE_SHARED_ACCESS_SIGNATURE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_access_signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blob_tier: Option<AzureBlobTier>,
+}
+
+impl AzureOptions {
+    pub fn environment(&self) -> Vec<(String, String)> {
+        environment(&[
+            ("AZURE_ACCOUNT_NAME", &self.account_name),
+            ("AZURE_ACCOUNT_KEY", &self.account_key),
+            (
+                "AZURE_SHARED_ACCESS_SIGNATURE",
+                &self.shared_access_signature,
+            ),
+        ])
+    }
+
+    pub fn flags(&self) -> Vec<Flag> {
+        let mut flags: Vec<Flag> = Vec::new();
+        if let Some(tier) = self.blob_tier {
+            flags.push(("--azure-blob-tier", vec![tier.as_arg().into()]));
+        }
+        flags
+    }
+}
+
+/// Options of the `swift://` backend.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct SwiftOptions {
+    /// Set as `SWIFT_USERNAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Set as `SWIFT_PASSWORD`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Set as `SWIFT_AUTHURL`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_url: Option<String>,
+    /// Set as `SWIFT_AUTHVERSION`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_version: Option<u8>,
+    /// Set as `SWIFT_TENANTNAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_name: Option<String>,
+    /// Set as `SWIFT_REGIONNAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_name: Option<String>,
+}
+
+impl SwiftOptions {
+    pub fn environment(&self) -> Vec<(String, String)> {
+        environment(&[
+            ("SWIFT_USERNAME", &self.username),
+            ("SWIFT_PASSWORD", &self.password),
+            ("SWIFT_AUTHURL", &self.auth_url),
+            (
+                "SWIFT_AUTHVERSION",
+                &self.auth_version.map(|v| v.to_string()),
+            ),
+            ("SWIFT_TENANTNAME", &self.tenant_name),
+            ("SWIFT_REGIONNAME", &self.region_name),
+        ])
+    }
+}
+
+/// Options of the backends which only take a password, such as `ftp://`,
+/// `webdav://` and `imap://`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct PasswordOptions {
+    /// Set as `FTP_PASSWORD`, which duplicity uses for all of these backends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl PasswordOptions {
+    pub fn environment(&self) -> Vec<(String, String)> {
+        environment(&[("FTP_PASSWORD", &self.password)])
+    }
+}
+
+/// Collect the variables which are set.
+fn environment(vars: &[(&str, &Option<String>)]) -> Vec<(String, String)> {
+    vars.iter()
+        .filter_map(|&(name, value)| value.as_ref().map(|v| (name.to_owned(), v.clone())))
+        .collect()
+}
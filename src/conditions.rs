@@ -0,0 +1,278 @@
+use chrono::{Datelike, Local, Timelike};
+use error::Error;
+use repository::is_false;
+use std::{fs, path};
+
+/// Default location of the power supply information within sysfs.
+const POWER_SUPPLY_ROOT: &str = "/sys/class/power_supply";
+
+/// Day of the week, as used in the `weekdays` condition.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    fn today() -> Self {
+        match Local::now().weekday() {
+            chrono::Weekday::Mon => Weekday::Mon,
+            chrono::Weekday::Tue => Weekday::Tue,
+            chrono::Weekday::Wed => Weekday::Wed,
+            chrono::Weekday::Thu => Weekday::Thu,
+            chrono::Weekday::Fri => Weekday::Fri,
+            chrono::Weekday::Sat => Weekday::Sat,
+            chrono::Weekday::Sun => Weekday::Sun,
+        }
+    }
+}
+
+/// Conditions which must all be satisfied for a repository to be run.
+///
+/// Unlike the sanity checks in `Repository::check()`, failing one of these
+/// conditions is not an error: the repository is simply skipped for this run.
+#[derive(Default, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct Conditions {
+    /// Only run when the machine is on AC power.
+    #[serde(skip_serializing_if = "is_false")]
+    pub ac_power: bool,
+    /// Location of the power supply information, which defaults to
+    /// `/sys/class/power_supply`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_supply_root: Option<String>,
+    /// Only run if the one-minute load average is at most this value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_load: Option<f64>,
+    /// Only run on the listed days of the week.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub weekdays: Vec<Weekday>,
+    /// Only run within one of the listed hour windows.  Each window is of the
+    /// form `START-END` (e.g. `22-6`), with `START` inclusive and `END`
+    /// exclusive, wrapping around midnight if `START` is greater than `END`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hours: Vec<String>,
+    /// Only run if all of the listed paths exist.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub path_exists: Vec<String>,
+    /// Only run if all of the listed paths are mount points.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mounted: Vec<String>,
+}
+
+/// Parse an hour window of the form `START-END`.
+fn parse_hours(window: &str) -> Result<(u32, u32), Error> {
+    let invalid = || {
+        Error::new(format!(
+            "Invalid hour window '{}'; it must be of the form 'START-END' with hours between 0 and 24.",
+            window
+        ))
+    };
+
+    let mut split = window.splitn(2, '-');
+    let start = split
+        .next()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .ok_or_else(invalid)?;
+    let end = split
+        .next()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .ok_or_else(invalid)?;
+
+    if start > 24 || end > 24 {
+        Err(invalid())
+    } else {
+        Ok((start, end))
+    }
+}
+
+/// Check whether the hour lies within the window.
+fn in_window(hour: u32, (start, end): (u32, u32)) -> bool {
+    if start <= end {
+        start <= hour && hour < end
+    } else {
+        start <= hour || hour < end
+    }
+}
+
+impl Conditions {
+    /// Check whether any condition has been specified.
+    pub fn is_empty(&self) -> bool {
+        *self == Conditions::default()
+    }
+
+    /// Check that the conditions are well formed.
+    pub fn check(&self) -> Result<(), Error> {
+        for window in &self.hours {
+            parse_hours(window)?;
+        }
+        if let Some(load) = self.max_load {
+            if load < 0.0 {
+                return Err(Error::new("The maximum load average cannot be negative."));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluate the conditions against the current state of the machine.
+    ///
+    /// If any condition is not met, the reason is returned within `Ok(Err(_))`
+    /// so that the repository can be skipped.  An error is only returned if
+    /// the state of the machine could not be determined.
+    pub fn evaluate(&self) -> Result<Result<(), String>, Error> {
+        if self.ac_power
+            && !on_ac_power(
+                self.power_supply_root
+                    .as_ref()
+                    .map_or(POWER_SUPPLY_ROOT, String::as_str),
+            )?
+        {
+            return Ok(Err("the machine is not on AC power".to_owned()));
+        }
+
+        if let Some(max_load) = self.max_load {
+            let load = load_average()?;
+            if load > max_load {
+                return Ok(Err(format!(
+                    "the load average {:.2} exceeds the maximum of {:.2}",
+                    load, max_load
+                )));
+            }
+        }
+
+        if !self.weekdays.is_empty() {
+            let today = Weekday::today();
+            if !self.weekdays.contains(&today) {
+                return Ok(Err(format!("backups are not allowed on {:?}", today)));
+            }
+        }
+
+        if !self.hours.is_empty() {
+            let hour = Local::now().hour();
+            let mut allowed = false;
+            for window in &self.hours {
+                allowed |= in_window(hour, parse_hours(window)?);
+            }
+            if !allowed {
+                return Ok(Err(format!(
+                    "the current hour ({}) is outside of the allowed windows",
+                    hour
+                )));
+            }
+        }
+
+        for p in &self.path_exists {
+            if !path::Path::new(p).exists() {
+                return Ok(Err(format!("the path {} does not exist", p)));
+            }
+        }
+
+        if !self.mounted.is_empty() {
+            let mount_points = mount_points()?;
+            for p in &self.mounted {
+                let p = path::Path::new(p)
+                    .canonicalize()
+                    .unwrap_or_else(|_| path::PathBuf::from(p));
+                if !mount_points.contains(&p) {
+                    return Ok(Err(format!("{} is not mounted", p.display())));
+                }
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+/// Determine whether the machine is on AC power.
+///
+/// This is the case if any mains power supply is online, or if there are no
+/// batteries at all (as is the case for most desktops).
+fn on_ac_power(root: &str) -> Result<bool, Error> {
+    let entries = fs::read_dir(root).map_err(|e| {
+        Error::new(format!(
+            "Error when reading power supply information from {}: {}",
+            root, e
+        ))
+    })?;
+
+    let mut has_battery = false;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| Error::new(format!("Error when reading power supply entry: {}", e)))?
+            .path();
+        let kind = fs::read_to_string(entry.join("type")).unwrap_or_default();
+        let online = fs::read_to_string(entry.join("online")).unwrap_or_default();
+        match (kind.trim(), online.trim()) {
+            ("Mains", "1") | ("USB", "1") => return Ok(true),
+            ("Battery", _) => has_battery = true,
+            _ => {}
+        }
+    }
+
+    Ok(!has_battery)
+}
+
+/// Get the one-minute load average.
+fn load_average() -> Result<f64, Error> {
+    fs::read_to_string("/proc/loadavg")
+        .map_err(|e| Error::new(format!("Error when reading the load average: {}", e)))?
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::new("Unable to parse the load average from /proc/loadavg."))
+}
+
+/// List all the current mount points.
+fn mount_points() -> Result<Vec<path::PathBuf>, Error> {
+    Ok(fs::read_to_string("/proc/mounts")
+        .map_err(|e| Error::new(format!("Error when reading mount points: {}", e)))?
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|p| path::PathBuf::from(p.replace("\\040", " ")))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{in_window, parse_hours, Conditions};
+
+    #[test]
+    fn hour_windows() {
+        assert_eq!(parse_hours("9-17").unwrap(), (9, 17));
+        assert_eq!(parse_hours(" 22 - 6 ").unwrap(), (22, 6));
+        assert!(parse_hours("9").is_err());
+        assert!(parse_hours("9-25").is_err());
+        assert!(parse_hours("a-b").is_err());
+
+        assert!(in_window(9, (9, 17)));
+        assert!(!in_window(17, (9, 17)));
+        assert!(in_window(23, (22, 6)));
+        assert!(in_window(3, (22, 6)));
+        assert!(!in_window(12, (22, 6)));
+    }
+
+    #[test]
+    fn evaluate() {
+        assert_eq!(Conditions::default().evaluate().unwrap(), Ok(()));
+
+        let missing = Conditions {
+            path_exists: vec!["/nonexistent/duplicity-front".to_owned()],
+            ..Default::default()
+        };
+        assert!(missing.evaluate().unwrap().is_err());
+
+        let unreadable = Conditions {
+            ac_power: true,
+            power_supply_root: Some("/nonexistent/duplicity-front".to_owned()),
+            ..Default::default()
+        };
+        assert!(unreadable.evaluate().is_err());
+    }
+}
@@ -1,117 +1,875 @@
+use diagnostic::{self, Diagnostic};
 use error::Error;
+use interpolate::{self, Context};
+use keys;
 use log::{debug, info, warn};
 use repository::Repository;
-use serde_yaml;
-use std::{collections::HashMap, fs::File, io, path};
+use resolve::{self, Resolver};
+use serde_yaml::{self, Mapping, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    ffi::OsStr,
+    fs, path,
+};
+
+/// Current version of the configuration file format.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Name of the configuration file within each configuration directory.
+const CONFIG_FILE_NAME: &str = "duplicity-front.yml";
+
+/// Options which are specific to a single repository and thus cannot be set
+/// within `defaults`.
+const NON_DEFAULT_OPTIONS: &[&str] = &["source", "remote", "sub_repositories"];
+
+/// Top-level configuration document.
+///
+/// The `defaults`, `templates` and `repositories` are kept as raw YAML so that
+/// they can be merged field by field before the repositories are
+/// deserialized.  Templates are never run themselves, and are only used by
+/// repositories (or other templates) which `extends` them.
+///
+/// The `vars` are user-defined variables which can be interpolated within the
+/// options of the repositories (see the `interpolate` module).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    version: u64,
+    #[serde(default, skip_serializing_if = "Mapping::is_empty")]
+    defaults: Mapping,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    vars: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Mapping::is_empty")]
+    templates: Mapping,
+    #[serde(default)]
+    repositories: Mapping,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+}
+
+impl Document {
+    /// Parse the document from YAML, handling the legacy format in which the
+    /// repositories are listed at the top level.
+    ///
+    /// The boolean returned indicates whether the legacy format was used.
+    ///
+    /// A document is versioned if it has a `version` which is not a mapping
+    /// (which would be a repository named `version` in the legacy format).  The
+    /// version may be given as a string of digits.
+    fn from_value(mut value: Value) -> Result<(Document, bool), Diagnostic> {
+        let versioned = match value {
+            Value::Mapping(ref mut m) => match m.get_mut(&Value::from("version")) {
+                None | Some(Value::Mapping(_)) => false,
+                Some(version) => {
+                    if let Some(n) = version.as_str().and_then(|v| v.trim().parse::<u64>().ok()) {
+                        *version = Value::from(n);
+                    }
+                    if !version.is_u64() {
+                        return Err(Diagnostic::new(
+                            "The configuration file version must be a number (e.g. 1).",
+                        )
+                        .key("version"));
+                    }
+                    true
+                }
+            },
+            Value::Null => false,
+            _ => {
+                return Err(Diagnostic::new(
+                    "Error when parsing configuration file: expected a mapping at the top level.",
+                ))
+            }
+        };
+
+        if versioned {
+            let document: Document = serde_yaml::from_value(value).map_err(|e| {
+                Diagnostic::from_serde(&e).context("Error when parsing configuration file")
+            })?;
+            if document.version > CONFIG_VERSION {
+                return Err(Diagnostic::new(format!(
+                    "Configuration file version {} is not supported; the latest supported version is {}.",
+                    document.version, CONFIG_VERSION
+                ))
+                .key("version"));
+            }
+            Ok((document, false))
+        } else {
+            let repositories = match value {
+                Value::Mapping(m) => m,
+                _ => Mapping::new(),
+            };
+            Ok((
+                Document {
+                    version: CONFIG_VERSION,
+                    defaults: Mapping::new(),
+                    vars: BTreeMap::new(),
+                    templates: Mapping::new(),
+                    repositories,
+                    include: Vec::new(),
+                },
+                true,
+            ))
+        }
+    }
+}
+
+/// Key marking a template or repository as replacing an earlier definition
+/// with the same name from another file.
+const OVERRIDE: &str = "override";
+
+/// A configuration document along with the file it was loaded from.
+struct Source {
+    path: path::PathBuf,
+    document: Document,
+}
+
+/// Read a configuration document, warning if it uses the legacy format.
+fn read_document(text: &str, path: &path::Path) -> Result<Document, Error> {
+    let mut value: Value = serde_yaml::from_str(text)
+        .map_err(|e| diagnostic::into_error(vec![Diagnostic::from_parse_error(&e, path, text)]))?;
+    keys::preserve_text(text, &mut value);
+    let (document, legacy) = Document::from_value(value)
+        .map_err(|d| diagnostic::into_error(vec![d.locate_in(path, text)]))?;
+    if legacy {
+        warn!(
+            "The configuration file {} does not specify a version and uses the deprecated flat \
+             format.  Use 'duplicity-front config migrate' to update it.",
+            path.display()
+        );
+    }
+    Ok(document)
+}
+
+/// Load the configuration file at the given path, along with all the files it
+/// includes.
+///
+/// Files are appended to `sources` in the order in which they are loaded:
+/// each file comes before the files it includes, which are loaded in the
+/// order listed (with the matches of a glob sorted by path).  Files which have
+/// already been loaded are skipped.
+fn load_sources(
+    p: &path::Path,
+    sources: &mut Vec<Source>,
+    seen: &mut HashSet<path::PathBuf>,
+) -> Result<(), Error> {
+    Config::check_permissions(&p)?;
+
+    let p = p.canonicalize().map_err(|e| {
+        Error::new(format!(
+            "Error when canonicalizing configuration path {}: {}",
+            p.display(),
+            e
+        ))
+    })?;
+    if !seen.insert(p.clone()) {
+        debug!("Skipping {} as it has already been loaded.", p.display());
+        return Ok(());
+    }
+
+    debug!("Loading configuration from file: {}", p.display());
+    let document = fs::read_to_string(&p)
+        .map_err(|e| {
+            Error::new(format!(
+                "Error when opening configuration file {}: {}",
+                p.display(),
+                e
+            ))
+        })
+        .and_then(|text| read_document(&text, &p))?;
+
+    let dir = p
+        .parent()
+        .unwrap_or_else(|| path::Path::new("/"))
+        .to_path_buf();
+    let include = document.include.clone();
+    sources.push(Source {
+        path: p.clone(),
+        document,
+    });
+
+    for pattern in include {
+        let pattern = dir.join(Config::resolve_path(&pattern)?);
+        let pattern = pattern.to_string_lossy();
+        let mut matches = glob::glob(&pattern)
+            .map_err(|e| {
+                Error::new(format!(
+                    "Invalid include pattern '{}' in {}: {}",
+                    pattern,
+                    p.display(),
+                    e
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::new(format!("Error when including {}: {}", pattern, e)))?;
+        if matches.is_empty() && !glob_special(&pattern) {
+            return Err(Error::new(format!(
+                "Included configuration file {} (from {}) does not exist.",
+                pattern,
+                p.display()
+            )));
+        }
+        matches.sort();
+        for m in matches {
+            load_sources(&m, sources, seen)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the files within the `.d` directory next to the configuration file,
+/// in order of their path.
+fn load_conf_d(
+    p: &path::Path,
+    sources: &mut Vec<Source>,
+    seen: &mut HashSet<path::PathBuf>,
+) -> Result<(), Error> {
+    let (dir, stem) = match (p.parent(), p.file_stem()) {
+        (Some(dir), Some(stem)) => (dir, stem),
+        _ => return Ok(()),
+    };
+    let mut conf_d = stem.to_os_string();
+    conf_d.push(".d");
+    let conf_d = dir.join(conf_d);
+    if !conf_d.is_dir() {
+        return Ok(());
+    }
+
+    let pattern = conf_d.join("*.yml");
+    let mut matches = glob::glob(&pattern.to_string_lossy())
+        .map_err(|e| Error::new(format!("Invalid configuration directory: {}", e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            Error::new(format!(
+                "Error when reading configuration directory {}: {}",
+                conf_d.display(),
+                e
+            ))
+        })?;
+    matches.sort();
+    for m in matches {
+        load_sources(&m, sources, seen)?;
+    }
+    Ok(())
+}
+
+/// Locations searched for configuration files, from lowest to highest
+/// precedence:
+///
+/// 1. `/etc/duplicity-front.yml`;
+/// 2. `duplicity-front.yml` within each of `$XDG_CONFIG_DIRS` (`/etc/xdg` by
+///    default), with earlier directories taking precedence over later ones;
+/// 3. `duplicity-front.yml` within `$XDG_CONFIG_HOME` (`~/.config` by
+///    default).
+pub fn search_paths() -> Vec<path::PathBuf> {
+    search_paths_from(
+        env::var_os("XDG_CONFIG_DIRS").as_deref(),
+        dirs::config_dir().as_deref(),
+    )
+}
+
+/// Locations searched for configuration files, as for `search_paths`, given
+/// the value of `$XDG_CONFIG_DIRS` and the user's configuration directory.
+fn search_paths_from(
+    config_dirs: Option<&OsStr>,
+    config_home: Option<&path::Path>,
+) -> Vec<path::PathBuf> {
+    let mut paths = vec![path::Path::new("/etc").join(CONFIG_FILE_NAME)];
+
+    let config_dirs = config_dirs
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| OsStr::new("/etc/xdg"));
+    let mut system: Vec<_> = env::split_paths(config_dirs)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .collect();
+    system.reverse();
+    paths.extend(system);
+
+    if let Some(dir) = config_home {
+        paths.push(dir.join(CONFIG_FILE_NAME));
+    }
+
+    let mut seen = HashSet::new();
+    paths.retain(|p| seen.insert(p.clone()));
+    paths
+}
+
+/// Check whether the pattern contains any glob special characters.
+fn glob_special(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Remove the override marker from an entry, returning whether it was set.
+fn take_override(value: &mut Value) -> Result<bool, Error> {
+    match value {
+        Value::Mapping(m) => match m.remove(&Value::from(OVERRIDE)) {
+            None => Ok(false),
+            Some(Value::Bool(b)) => Ok(b),
+            Some(_) => Err(Error::new("'override' must be either true or false.")),
+        },
+        _ => Ok(false),
+    }
+}
+
+/// Add the entries from `from` into `into`, failing if an entry is defined
+/// twice without being marked as an override.
+fn combine_entries(
+    kind: &str,
+    into: &mut Mapping,
+    origins: &mut HashMap<Value, path::PathBuf>,
+    from: Mapping,
+    path: &path::Path,
+) -> Result<(), Error> {
+    for (name, mut value) in from {
+        let replace = take_override(&mut value).map_err(|e| {
+            Error::new(format!(
+                "Error in {} {:?} in {}: {}",
+                kind.to_lowercase(),
+                name,
+                path.display(),
+                e
+            ))
+        })?;
+        if let Some(origin) = origins.get(&name) {
+            if !replace {
+                return Err(Error::new(format!(
+                    "{} {} is defined in both {} and {}.  Set 'override: true' on the later \
+                     definition in order to replace the earlier one.",
+                    kind,
+                    entry_name(&name, kind)?,
+                    origin.display(),
+                    path.display()
+                )));
+            }
+            info!(
+                "{} {} from {} is overridden by {}.",
+                kind,
+                entry_name(&name, kind)?,
+                origin.display(),
+                path.display()
+            );
+        }
+        origins.insert(name.clone(), path.to_path_buf());
+        into.insert(name, value);
+    }
+    Ok(())
+}
+
+impl Document {
+    /// Combine the documents loaded from several files into one.
+    ///
+    /// The defaults and variables are merged in the order the files were
+    /// loaded, with later files taking precedence.  Templates and repositories
+    /// may only be defined once, unless the later definition sets
+    /// `override: true` in which case it replaces the earlier one entirely.
+    ///
+    /// The file in which each repository was (last) defined is returned
+    /// alongside the document.
+    fn combine(sources: Vec<Source>) -> Result<(Document, HashMap<String, path::PathBuf>), Error> {
+        let mut defaults = Value::Null;
+        let mut vars = BTreeMap::new();
+        let mut templates = Mapping::new();
+        let mut repositories = Mapping::new();
+        let mut template_origins = HashMap::new();
+        let mut repository_origins = HashMap::new();
+
+        for Source { path, document } in sources {
+            defaults =
+                resolve::merge(&defaults, Value::Mapping(document.defaults)).map_err(|e| {
+                    Error::new(format!("Error in defaults in {}: {}", path.display(), e))
+                })?;
+            vars.extend(document.vars);
+            combine_entries(
+                "Template",
+                &mut templates,
+                &mut template_origins,
+                document.templates,
+                &path,
+            )?;
+            combine_entries(
+                "Repository",
+                &mut repositories,
+                &mut repository_origins,
+                document.repositories,
+                &path,
+            )?;
+        }
+
+        let origins = repository_origins
+            .into_iter()
+            .filter_map(|(name, path)| name.as_str().map(|name| (name.to_owned(), path)))
+            .collect();
+
+        Ok((
+            Document {
+                version: CONFIG_VERSION,
+                defaults: match defaults {
+                    Value::Mapping(defaults) => defaults,
+                    _ => Mapping::new(),
+                },
+                vars,
+                templates,
+                repositories,
+                include: Vec::new(),
+            },
+            origins,
+        ))
+    }
+}
+
+/// Get the name of a template or repository, which must be a string.
+fn entry_name<'a>(name: &'a Value, kind: &str) -> Result<&'a str, Error> {
+    name.as_str()
+        .ok_or_else(|| Error::new(format!("{} names must be strings, found {:?}.", kind, name)))
+}
 
 pub struct Config {
+    /// Repositories, with all variables expanded.
     pub repositories: HashMap<String, Repository>,
+    /// Repositories as written, before the variables are expanded.
+    ///
+    /// These are kept as YAML since the options which are not strings (such
+    /// as times) can only be parsed once expanded.
+    pub raw: HashMap<String, Value>,
+    /// User-defined variables.
+    pub vars: BTreeMap<String, String>,
+    /// Files from which the configuration was loaded, in the order loaded.
+    pub files: Vec<path::PathBuf>,
+    /// File in which each repository is defined.
+    pub origins: HashMap<String, path::PathBuf>,
+    /// Repositories hidden as they do not apply to the current host or user,
+    /// along with the reason.
+    pub hidden: HashMap<String, Error>,
 }
 
 impl Config {
-    /// Load configuration from file.
+    /// Resolve the path to the configuration file.
     ///
-    /// This reads the configuration from the specified file and checks that it
-    /// is sane.
-    pub fn parse_file<S>(s: &S) -> Result<Config, Error>
+    /// If the path starts with '~', it is replaced with the home directory.
+    pub fn resolve_path<S>(s: &S) -> Result<path::PathBuf, Error>
     where
         S: AsRef<path::Path>,
     {
-        info!("Loading configuration from file: {}", s.as_ref().display());
-
         // If the path starts with '~', we have to strip that and replace it
         // with the home_dir path, except that we have to ensure that `home_dir`
         // is well defined.
-        let p =
-            if s.as_ref().starts_with("~") {
-                dirs::home_dir()
-                    .ok_or_else(|| {
-                        Error::new(
-                    "Config path starts with '~' but the home directory could not be located.",
-                )
-                    })
-                    .map(|home_path| {
-                        home_path.join(s.as_ref().strip_prefix("~").expect(
+        if s.as_ref().starts_with("~") {
+            dirs::home_dir()
+                .ok_or_else(|| {
+                    Error::new(
+                        "Config path starts with '~' but the home directory could not be located.",
+                    )
+                })
+                .map(|home_path| {
+                    home_path.join(
+                        s.as_ref().strip_prefix("~").expect(
                             "Unable to strip prefix.  This is a bug and should be reported.",
-                        ))
-                    })?
-            } else {
-                s.as_ref().to_path_buf()
-            };
+                        ),
+                    )
+                })
+        } else {
+            Ok(s.as_ref().to_path_buf())
+        }
+    }
+
+    /// Load configuration from several files.
+    ///
+    /// This reads the configuration from the specified files and checks that
+    /// it is sane.  The files are listed from lowest to highest precedence.
+    /// For each, files listed under `include` are loaded as well, followed by
+    /// the files within the `.d` directory next to the configuration file
+    /// (e.g. `duplicity-front.d/*.yml` for `duplicity-front.yml`).
+    pub fn parse_files<S>(files: &[S]) -> Result<Config, Error>
+    where
+        S: AsRef<path::Path>,
+    {
+        let mut sources = Vec::new();
+        let mut seen = HashSet::new();
+        for s in files {
+            info!("Loading configuration from file: {}", s.as_ref().display());
+            let p = Config::resolve_path(s)?;
+            load_sources(&p, &mut sources, &mut seen)?;
+            load_conf_d(&p, &mut sources, &mut seen)?;
+        }
+
+        let files = sources.iter().map(|source| source.path.clone()).collect();
+        let (document, origins) = Document::combine(sources)?;
+        let mut config = Config::build(document, origins, |d, origins| d.locate(origins))?;
+        config.files = files;
+        Ok(config)
+    }
+
+    /// Locate the configuration files to load, from lowest to highest
+    /// precedence.
+    ///
+    /// If a file is given explicitly (with `--config` or the
+    /// `DUPLICITY_FRONT_CONFIG` environment variable), only that file is
+    /// used.  Otherwise, all the files within `search_paths` which exist are
+    /// used.
+    pub fn locate(explicit: Option<&str>) -> Result<Vec<path::PathBuf>, Error> {
+        if let Some(p) = explicit {
+            return Ok(vec![Config::resolve_path(&p)?]);
+        }
+
+        let candidates = search_paths();
+        let found: Vec<_> = candidates.iter().filter(|p| p.is_file()).cloned().collect();
+        if found.is_empty() {
+            return Err(Error::new(format!(
+                "No configuration file could be found.  Searched: {}.",
+                candidates
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        Ok(found)
+    }
+
+    /// Locate and load the configuration.
+    pub fn load(explicit: Option<&str>) -> Result<Config, Error> {
+        Config::parse_files(&Config::locate(explicit)?)
+    }
+
+    #[cfg(test)]
+    pub fn from_reader<I>(mut reader: I) -> Result<Self, Error>
+    where
+        I: ::std::io::Read,
+    {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .map_err(|e| Error::new(e.to_string()))?;
+        Config::from_text(&text, path::Path::new("<reader>"))
+    }
+
+    /// Load the configuration from a single file's text, without any includes.
+    ///
+    /// `path` is only used to locate the problems found.
+    pub fn from_text(text: &str, path: &path::Path) -> Result<Self, Error> {
+        let document = read_document(text, path)?;
+        let (document, _) = Document::combine(vec![Source {
+            path: path.to_owned(),
+            document,
+        }])?;
+        Config::build(document, HashMap::new(), |d, _| d.locate_in(path, text))
+    }
+
+    /// Construct the configuration from the document and check that it is
+    /// sane, locating each of the problems found with `locate`.
+    ///
+    /// The problems found while deserializing the repositories and those
+    /// found by `check` are all reported at once, sorted by repository.
+    fn build<F>(
+        document: Document,
+        origins: HashMap<String, path::PathBuf>,
+        locate: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(Diagnostic, &HashMap<String, path::PathBuf>) -> Diagnostic,
+    {
+        let (config, mut diagnostics, failed) = Config::from_document(document, origins)?;
+        diagnostics.extend(config.check(&failed));
+        if diagnostics.is_empty() {
+            return Ok(config);
+        }
+
+        diagnostics.sort_by(|a, b| a.repository_name().cmp(&b.repository_name()));
+        Err(diagnostic::into_error(
+            diagnostics
+                .into_iter()
+                .map(|d| locate(d, &config.origins))
+                .collect(),
+        ))
+    }
+
+    /// Construct the configuration from the document, resolving the defaults
+    /// and templates of every repository.
+    ///
+    /// Relative paths within each repository are resolved against the
+    /// directory of the file in which it is defined, as given in `origins`,
+    /// or the current directory if it is not listed.
+    ///
+    /// Repositories which cannot be deserialized are left out of the
+    /// configuration; the problems found are returned alongside it, with the
+    /// names of those repositories.
+    #[allow(clippy::type_complexity)]
+    fn from_document(
+        document: Document,
+        origins: HashMap<String, path::PathBuf>,
+    ) -> Result<(Self, Vec<Diagnostic>, HashSet<String>), Error> {
+        let current_dir = env::current_dir()
+            .map_err(|e| Error::new(format!("Unable to determine the current directory: {}", e)))?;
+
+        for option in NON_DEFAULT_OPTIONS {
+            if document.defaults.contains_key(&Value::from(*option)) {
+                return Err(Error::new(format!(
+                    "The option '{}' cannot be specified within the defaults.",
+                    option
+                )));
+            }
+        }
+        let defaults = resolve::merge(&Value::Null, Value::Mapping(document.defaults))
+            .map_err(|e| Error::new(format!("Error in defaults: {}", e)))?;
+        serde_yaml::from_value::<Repository>(defaults.clone()).map_err(|e| {
+            diagnostic::into_error(vec![Diagnostic::from_serde(&e).context("Error in defaults")])
+        })?;
 
-        Config::check_permissions(&p)?;
+        // Errors within templates and repositories are collected so that they
+        // can all be reported at once.
+        let mut diagnostics = Vec::new();
+        let mut resolver = Resolver::new(&document.templates);
+        for name in document.templates.iter().map(|(name, _)| name) {
+            let name = entry_name(name, "Template")?;
+            if let Err(e) =
+                serde_yaml::from_value::<Repository>(resolver.template(&defaults, name)?)
+            {
+                diagnostics.push(
+                    Diagnostic::from_serde(&e).context(format!("Error in template {}", name)),
+                );
+            }
+        }
+
+        for name in document.vars.keys() {
+            if interpolate::BUILTINS.contains(&name.as_str()) || name.contains(':') {
+                return Err(Error::new(format!(
+                    "'{}' cannot be used as the name of a variable.",
+                    name
+                )));
+            }
+        }
+
+        // Variables are expanded leniently here, leaving any string which
+        // cannot be expanded as is; `check` reports the errors.
+        let mut repositories = HashMap::new();
+        let mut raw = HashMap::new();
+        let mut failed = HashSet::new();
+        let vars = &document.vars;
+        for (name, repository) in document.repositories {
+            let name = entry_name(&name, "Repository")?.to_owned();
+            let base = origins
+                .get(&name)
+                .and_then(|origin| origin.parent())
+                .unwrap_or(&current_dir);
+            let parsed = resolver
+                .resolve(&defaults, repository)
+                .map_err(Diagnostic::from)
+                .and_then(|value| {
+                    let expanded = Context::new(&name, vars)
+                        .expand_repository(&value)
+                        .unwrap_or_else(|_| value.clone());
+                    let mut repository: Repository =
+                        serde_yaml::from_value(expanded).map_err(|e| Diagnostic::from_serde(&e))?;
+                    repository.resolve_paths(base)?;
+                    Ok((value, repository))
+                });
+
+            match parsed {
+                Ok((raw_repository, repository)) => {
+                    raw.insert(name.clone(), raw_repository);
+                    repositories.insert(name, repository);
+                }
+                Err(d) => {
+                    diagnostics.push(d.repository(name.as_str()));
+                    failed.insert(name);
+                }
+            }
+        }
+
+        let config = Config {
+            repositories,
+            raw,
+            vars: document.vars,
+            files: Vec::new(),
+            origins,
+            hidden: HashMap::new(),
+        };
+        Ok((config, diagnostics, failed))
+    }
+
+    /// Migrate the configuration file to the latest format.
+    ///
+    /// The original file is kept alongside the new one with a `.bak`
+    /// extension.  If `dry_run` is set, the migrated configuration is printed
+    /// instead of written.
+    pub fn migrate_file<S>(s: &S, dry_run: bool) -> Result<(), Error>
+    where
+        S: AsRef<path::Path>,
+    {
+        let p = Config::resolve_path(s)?;
+        let text = fs::read_to_string(&p)
+            .map_err(|e| Error::new(format!("Error when opening configuration file: {}", e)))?;
+        let mut value: Value = serde_yaml::from_str(&text)
+            .map_err(|e| Error::new(format!("Error when parsing configuration file: {}", e)))?;
+        keys::preserve_text(&text, &mut value);
+
+        let (document, legacy) =
+            Document::from_value(value).map_err(|d| diagnostic::into_error(vec![d]))?;
+        if !legacy {
+            info!(
+                "Configuration file is already at version {}.",
+                document.version
+            );
+            return Ok(());
+        }
+
+        // Make sure the result is valid before writing anything.
+        let (combined, origins) = Document::combine(vec![Source {
+            path: p.clone(),
+            document: document.clone(),
+        }])?;
+        Config::build(combined, origins, |d, origins| d.locate(origins))?;
+
+        let migrated = serde_yaml::to_string(&document)
+            .map_err(|e| Error::new(format!("Error when serializing configuration file: {}", e)))?;
+
+        if dry_run {
+            println!("{}", migrated);
+            return Ok(());
+        }
 
-        let p = p.canonicalize().map_err(|e| {
+        let mut backup = p.clone().into_os_string();
+        backup.push(".bak");
+        let backup = path::PathBuf::from(backup);
+        fs::copy(&p, &backup).map_err(|e| {
             Error::new(format!(
-                "Error when canonicalizing configuration path: {}",
+                "Error when backing up configuration file to {}: {}",
+                backup.display(),
                 e
             ))
         })?;
+        fs::write(&p, migrated + "\n")
+            .map_err(|e| Error::new(format!("Error when writing configuration file: {}", e)))?;
+        warn!(
+            "Configuration file migrated to version {}.  Comments are not preserved; the original \
+             file was saved as {}.",
+            CONFIG_VERSION,
+            backup.display()
+        );
 
-        File::open(&p)
-            .map_err(|e| Error::new(format!("Error when opening configuration file: {}", e)))
-            .and_then(Config::from_reader)
+        Ok(())
     }
 
-    fn from_reader<I>(reader: I) -> Result<Self, Error>
-    where
-        I: io::Read,
-    {
-        serde_yaml::from_reader(reader)
-            .map(|repositories| Config { repositories })
-            .map_err(|e| Error::new(format!("Error when parsing configuration file: {}", e)))
-            .and_then(|config| config.check().and(Ok(config)))
+    /// Find the repositories which do not apply to the given host and user,
+    /// along with the reason.
+    pub fn not_applying_to(&self, host: &str, user: &str) -> HashMap<String, Error> {
+        self.repositories
+            .iter()
+            .filter_map(|(name, repository)| {
+                repository
+                    .applies_to(host, user)
+                    .err()
+                    .map(|reason| (name.clone(), reason))
+            })
+            .collect()
+    }
+
+    /// Hide the repositories which do not apply to the given host and user.
+    ///
+    /// Hidden repositories are removed from `repositories` and from the
+    /// sub-repositories of any group listing them.
+    pub fn select(&mut self, host: &str, user: &str) {
+        for (name, reason) in self.not_applying_to(host, user) {
+            info!("Hiding repository {}: {}.", name, reason);
+            self.hidden.insert(name, reason);
+        }
+
+        let hidden = &self.hidden;
+        self.repositories
+            .retain(|name, _| !hidden.contains_key(name));
+        for (name, repository) in &mut self.repositories {
+            repository.sub_repositories.retain(|sub_name| {
+                let keep = !hidden.contains_key(sub_name);
+                if !keep {
+                    info!(
+                        "Omitting hidden repository {} from repository {}.",
+                        sub_name, name
+                    );
+                }
+                keep
+            });
+        }
     }
 
     /// Check that the configuration is sane.
     ///
-    /// This will check that each repository is sane, and that sub-repositories
-    /// listed in one exist within the same configuration.
-    pub fn check(&self) -> Result<(), Error> {
+    /// This will check that the variables within each repository can be
+    /// expanded, that each repository is sane, and that sub-repositories
+    /// listed in one exist within the same configuration.  The repositories
+    /// in `failed` could not be deserialized; they are not reported as
+    /// missing when listed as sub-repositories.
+    ///
+    /// The problems found are returned without being located.
+    fn check(&self, failed: &HashSet<String>) -> Vec<Diagnostic> {
         debug!("Checking configuration is sane.");
 
-        for (name, repository) in &self.repositories {
+        let mut names: Vec<_> = self.repositories.keys().collect();
+        names.sort();
+
+        let mut diagnostics = Vec::new();
+        for name in names {
+            let repository = &self.repositories[name];
+            let error = |e: Error| Diagnostic::from(e).repository(name.as_str());
+
+            if let Some(raw) = self.raw.get(name) {
+                if let Err(e) = Context::new(name, &self.vars).expand_repository(raw) {
+                    diagnostics.push(error(e));
+                }
+            }
+
             if let Err(e) = repository.check() {
-                return Err(Error::new(format!("Error in repository {}: {}", name, e)));
+                diagnostics.push(error(e));
             }
 
             if repository.has_sub_repositories() {
                 for sub_name in &repository.sub_repositories {
-                    if !self.repositories.contains_key(sub_name) {
-                        return Err(Error::new(format!(
-                            "Repository {} lists {} as a sub-repository, but it could not be located within config file.",
-                            name,
-                            sub_name
-                        )));
+                    if self.repositories.contains_key(sub_name) || failed.contains(sub_name) {
+                        continue;
                     }
+                    let mut d = Diagnostic::new(format!(
+                        "{} is listed as a sub-repository, but it could not be located within the configuration.",
+                        sub_name
+                    ))
+                    .repository(name.as_str())
+                    .key("sub_repositories");
+                    let names = self.repositories.keys().chain(failed);
+                    if let Some(similar) = diagnostic::suggest(sub_name, names.map(String::as_str))
+                    {
+                        d = d.help(format!("did you mean `{}`?", similar));
+                    }
+                    diagnostics.push(d);
                 }
             }
         }
-
-        Ok(())
+        diagnostics
     }
 
     #[cfg(target_family = "unix")]
     /// Check the permissions on the config file and warn the user if they are
     /// readable to anyone else but the user only.
     ///
+    /// Files owned by another user (such as the system-wide configuration)
+    /// are not checked as the user cannot change their permissions.
+    ///
     /// This currently only works on Unix.
     fn check_permissions<S>(s: &S) -> Result<(), Error>
     where
         S: AsRef<path::Path>,
     {
         use std::fs;
-        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
-        let mode = fs::metadata(s)
-            .map_err(|e| Error::new(format!("Error when getting config permissions: {}", e)))?
-            .permissions()
-            .mode();
+        let metadata = fs::metadata(s)
+            .map_err(|e| Error::new(format!("Error when getting config permissions: {}", e)))?;
+        let mode = metadata.permissions().mode();
 
         // Check that
-        if mode & 0o077 != 0 {
+        if metadata.uid() == unsafe { libc::getuid() } && mode & 0o077 != 0 {
             warn!(
-                "It is recommended that your configuration file be not readable to anyone except for the user."
+                "It is recommended that your configuration file ({}) be not readable to anyone except for the user.",
+                s.as_ref().display()
             );
         }
 
@@ -131,3 +889,279 @@ impl Config {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{search_paths_from, Config};
+    use keys::KeyList;
+    use std::{env, fs, path, process};
+
+    /// Write a configuration file within the fixture directory.
+    fn fixture(dir: &path::Path, name: &str, text: &str) -> path::PathBuf {
+        let p = dir.join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(&p, text).unwrap();
+        p
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_reader(
+            r#"
+version: 1
+defaults:
+  encrypt_key: ABCD1234
+  volsize: 100
+  conditions:
+    max_load: 2.0
+repositories:
+  foo:
+    source: ~/
+    remote: file:///backup/foo
+    volsize: 200
+    conditions:
+      weekdays: [mon]
+  bar:
+    source: ~/
+    remote: file:///backup/bar
+    encrypt_key: ~
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let foo = &config.repositories["foo"];
+        assert_eq!(foo.encrypt_key, KeyList(vec!["ABCD1234".to_owned()]));
+        assert_eq!(foo.volsize, Some(200));
+        assert_eq!(foo.conditions.max_load, Some(2.0));
+        assert_eq!(foo.conditions.weekdays.len(), 1);
+
+        let bar = &config.repositories["bar"];
+        assert!(bar.encrypt_key.is_empty());
+        assert_eq!(bar.volsize, Some(100));
+
+        assert!(Config::from_reader(
+            r#"
+version: 1
+defaults:
+  source: ~/
+"#
+            .as_bytes(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn interpolation() {
+        let config = Config::from_reader(
+            r#"
+version: 1
+vars:
+  nas: sftp://nas/
+repositories:
+  foo:
+    source: /srv/${repo}
+    remote: ${nas}/backups/${repo}
+    log_file: /var/log/$$HOME/${repo}.log
+    passphrase: pa$$word${x}
+    env:
+      TOKEN: $${not}
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let foo = &config.repositories["foo"];
+        assert_eq!(foo.source.as_ref().unwrap().to_string(), "/srv/foo");
+        assert_eq!(
+            foo.remote.as_ref().unwrap().to_string(),
+            "sftp://nas//backups/foo"
+        );
+        assert_eq!(
+            foo.log_file.as_ref().unwrap().to_string(),
+            "/var/log/$HOME/foo.log"
+        );
+        assert_eq!(foo.passphrase.as_deref(), Some("pa$$word${x}"));
+        assert_eq!(foo.env["TOKEN"], "$${not}");
+    }
+
+    #[test]
+    fn legacy() {
+        let config = Config::from_reader(
+            r#"
+foo:
+  source: ~/
+  remote: file:///backup/foo
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        assert!(config.repositories.contains_key("foo"));
+
+        assert!(Config::from_reader("version: 2\nrepositories: {}".as_bytes()).is_err());
+
+        let config = Config::from_reader(
+            "version: \"1\"\nrepositories:\n  foo: {source: /, remote: file:///backup/foo}"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(config.repositories.contains_key("foo"));
+        let error = Config::from_reader("version: one\nrepositories: {}".as_bytes())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("version must be a number (e.g. 1)."));
+        assert!(error.contains("version: one"));
+    }
+
+    #[test]
+    fn diagnostics() {
+        let error = Config::from_reader(
+            r#"
+version: 1
+repositories:
+  all:
+    sub_repositories: [mine, mnie]
+  mine:
+    source: ~/
+    remote: file:///backup/mine
+    exlcude: [/tmp]
+"#
+            .as_bytes(),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+
+        // Both the unknown option and the missing sub-repository are reported,
+        // although `mine` itself cannot be deserialized.
+        assert!(error.starts_with("2 errors found in the configuration:"));
+        assert!(error.contains(
+            "Error in repository all: mnie is listed as a sub-repository, but it could not be \
+             located within the configuration.\n \
+             --> <reader>:5:5\n  |\n5 |     sub_repositories: [mine, mnie]\n  |     \
+             ^^^^^^^^^^^^^^^^\n  = help: did you mean `mine`?"
+        ));
+        assert!(error.contains(
+            "Error in repository mine: unknown option `exlcude`\n \
+             --> <reader>:9:5\n  |\n9 |     exlcude: [/tmp]\n  |     ^^^^^^^\n  \
+             = help: did you mean `exclude`?"
+        ));
+        assert!(error.find("repository all").unwrap() < error.find("repository mine").unwrap());
+    }
+
+    #[test]
+    fn includes() {
+        let dir = env::temp_dir().join(format!("duplicity-front-{}-includes", process::id()));
+        let main = fixture(
+            &dir,
+            "duplicity-front.yml",
+            "version: 1\ninclude: [extra/*.yml]\ndefaults: {volsize: 100}\n\
+             repositories:\n  a: {source: /a, remote: file:///backup/a}\n",
+        );
+        fixture(
+            &dir,
+            "extra/2.yml",
+            "version: 1\nrepositories:\n  c: {source: /c, remote: file:///backup/c}\n",
+        );
+        fixture(
+            &dir,
+            "extra/1.yml",
+            "version: 1\ndefaults: {volsize: 200}\n\
+             repositories:\n  b: {source: /b, remote: file:///backup/b}\n",
+        );
+        fixture(
+            &dir,
+            "duplicity-front.d/local.yml",
+            "version: 1\nrepositories:\n  \
+             a: {override: true, source: /local, remote: file:///backup/local}\n",
+        );
+
+        let config = Config::parse_files(&[&main]).unwrap();
+        let names: Vec<_> = config
+            .files
+            .iter()
+            .map(|p| p.strip_prefix(&dir).unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "duplicity-front.yml",
+                "extra/1.yml",
+                "extra/2.yml",
+                "duplicity-front.d/local.yml"
+            ]
+        );
+        assert_eq!(
+            config.repositories["a"]
+                .source
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "/local"
+        );
+        assert_eq!(config.repositories["c"].volsize, Some(200));
+        assert_eq!(config.origins["a"], dir.join("duplicity-front.d/local.yml"));
+        assert_eq!(config.origins["b"], dir.join("extra/1.yml"));
+
+        fixture(
+            &dir,
+            "duplicity-front.d/local.yml",
+            "version: 1\nrepositories:\n  b: {source: /local, remote: file:///backup/local}\n",
+        );
+        let error = Config::parse_files(&[&main]).err().unwrap().to_string();
+        assert!(error.contains("Repository b is defined in both"));
+        assert!(error.contains("Set 'override: true'"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layering() {
+        let dir = env::temp_dir().join(format!("duplicity-front-{}-layering", process::id()));
+        assert_eq!(
+            search_paths_from(
+                Some("/first:relative:/second".as_ref()),
+                Some(&dir.join("home"))
+            ),
+            [
+                path::PathBuf::from("/etc/duplicity-front.yml"),
+                path::PathBuf::from("/second/duplicity-front.yml"),
+                path::PathBuf::from("/first/duplicity-front.yml"),
+                dir.join("home/duplicity-front.yml"),
+            ]
+        );
+        assert_eq!(
+            search_paths_from(Some("".as_ref()), None),
+            [
+                path::PathBuf::from("/etc/duplicity-front.yml"),
+                path::PathBuf::from("/etc/xdg/duplicity-front.yml"),
+            ]
+        );
+
+        let system = fixture(
+            &dir,
+            "first/duplicity-front.yml",
+            "version: 1\ndefaults: {volsize: 100, remove_all_but_n_full: 3}\n\
+             repositories:\n  managed: {source: /srv, remote: file:///backup/srv}\n",
+        );
+        let user = fixture(
+            &dir,
+            "home/duplicity-front.yml",
+            "version: 1\ndefaults: {volsize: 200}\n\
+             repositories:\n  mine: {source: /home, remote: file:///backup/home}\n",
+        );
+        let config = Config::parse_files(&[&system, &user]).unwrap();
+        assert_eq!(config.repositories["managed"].volsize, Some(200));
+        assert_eq!(config.repositories["mine"].remove_all_but_n_full, Some(3));
+        assert_eq!(config.origins["managed"], system);
+        assert_eq!(config.origins["mine"], user);
+
+        assert_eq!(
+            Config::locate(Some(user.to_str().unwrap())).unwrap(),
+            [user]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+//! Diagnostics for errors within the configuration files.
+//!
+//! Errors are reported along with the file, line and column at which they
+//! occur and the offending line, in a similar way to `rustc`.  As the
+//! repositories are deserialized from merged YAML values which no longer
+//! carry positions, the location of a repository (or of one of its keys) is
+//! found by scanning the file in which it is defined.
+
+use error::Error;
+use std::collections::HashMap;
+use std::{fmt, fs, path};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// Minimum similarity for a name to be suggested in place of another.
+const SUGGESTION_THRESHOLD: f64 = 0.8;
+
+/// Position of an error within a configuration file.
+#[derive(Debug)]
+struct Location {
+    path: path::PathBuf,
+    /// Line number, starting at 1.
+    line: usize,
+    /// Column number, starting at 1.
+    column: usize,
+    /// Number of characters to highlight.
+    width: usize,
+    /// The offending line.
+    snippet: String,
+}
+
+/// An error within the configuration.
+#[derive(Debug)]
+pub struct Diagnostic {
+    message: String,
+    repository: Option<String>,
+    key: Option<String>,
+    help: Option<String>,
+    location: Option<Box<Location>>,
+}
+
+impl Diagnostic {
+    pub fn new<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Diagnostic {
+            message: message.into(),
+            repository: None,
+            key: None,
+            help: None,
+            location: None,
+        }
+    }
+
+    /// Create the diagnostic from a deserialization error.
+    ///
+    /// Unknown fields are reported along with the closest known field, if
+    /// any, instead of the full list of fields.
+    pub fn from_serde(error: &serde_yaml::Error) -> Self {
+        let message = error.to_string();
+        let unknown = message
+            .strip_prefix("unknown field `")
+            .and_then(|rest| rest.find('`').map(|end| rest[..end].to_owned()));
+        let unknown = match unknown {
+            Some(unknown) => unknown,
+            None => return Diagnostic::new(message),
+        };
+
+        let expected: Vec<_> = message
+            .find("expected one of ")
+            .map(|i| {
+                message[i + "expected one of ".len()..]
+                    .split(", ")
+                    .map(|field| field.trim_matches('`'))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let help = suggest(&unknown, expected).map(|field| format!("did you mean `{}`?", field));
+
+        let mut diagnostic = Diagnostic::new(format!("unknown option `{}`", unknown)).key(&unknown);
+        diagnostic.help = help;
+        diagnostic
+    }
+
+    /// Create the diagnostic from an error while parsing the YAML itself.
+    pub fn from_parse_error(error: &serde_yaml::Error, path: &path::Path, text: &str) -> Self {
+        let diagnostic = Diagnostic::new(format!(
+            "Error when parsing configuration file {}: {}",
+            path.display(),
+            error
+        ));
+        match error.location() {
+            Some(location) => diagnostic.at(path, text, location.line(), location.column(), 1),
+            None => diagnostic,
+        }
+    }
+
+    /// Name of the repository to which the diagnostic is attributed, if any.
+    pub fn repository_name(&self) -> Option<&str> {
+        self.repository.as_deref()
+    }
+
+    /// Attribute the diagnostic to a repository.
+    pub fn repository<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.repository = Some(name.into());
+        self
+    }
+
+    /// Attribute the diagnostic to a key within the repository.
+    pub fn key<S>(mut self, key: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Prefix the message with some context.
+    pub fn context<S>(mut self, context: S) -> Self
+    where
+        S: fmt::Display,
+    {
+        self.message = format!("{}: {}", context, self.message);
+        self
+    }
+
+    pub fn help<S>(mut self, help: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Set the location of the diagnostic.
+    fn at(
+        mut self,
+        path: &path::Path,
+        text: &str,
+        line: usize,
+        column: usize,
+        width: usize,
+    ) -> Self {
+        let snippet = text.lines().nth(line - 1).unwrap_or("").to_owned();
+        self.location = Some(Box::new(Location {
+            path: path.to_path_buf(),
+            line,
+            column,
+            width,
+            snippet,
+        }));
+        self
+    }
+
+    /// Locate the key (or the repository, if there is no key or it cannot be
+    /// found) within the file in which the repository is defined, as given in
+    /// `origins`.
+    pub fn locate(self, origins: &HashMap<String, path::PathBuf>) -> Self {
+        let path = match self.repository {
+            Some(ref name) => match origins.get(name) {
+                Some(path) => path.clone(),
+                None => return self,
+            },
+            None => return self,
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => self.locate_in(&path, &text),
+            Err(_) => self,
+        }
+    }
+
+    /// Locate the key (or the repository) within the given text.
+    ///
+    /// Without a repository, the key is looked up at the top level.
+    pub fn locate_in(self, path: &path::Path, text: &str) -> Self {
+        let keys = Keys::scan(text);
+        let found = match (self.repository.as_ref(), self.key.as_ref()) {
+            (Some(name), Some(key)) => keys
+                .find_within(name, key)
+                .or_else(|| keys.find_within(name, ""))
+                .or_else(|| keys.find(&[name])),
+            (Some(name), None) => keys.find(&[name]),
+            (None, Some(key)) => keys.find(&[key]),
+            (None, None) => None,
+        };
+        match found {
+            Some((marker, width)) => self.at(path, text, marker.line(), marker.col() + 1, width),
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.repository {
+            Some(ref name) => write!(f, "Error in repository {}: {}", name, self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+
+        let gutter = self
+            .location
+            .as_ref()
+            .map_or(1, |location| location.line.to_string().len());
+        if let Some(ref location) = self.location {
+            write!(
+                f,
+                "\n{:gutter$}--> {}:{}:{}\n{:gutter$} |\n{} | {}\n{:gutter$} | {:indent$}{}",
+                "",
+                location.path.display(),
+                location.line,
+                location.column,
+                "",
+                location.line,
+                location.snippet,
+                "",
+                "",
+                "^".repeat(location.width.max(1)),
+                gutter = gutter,
+                indent = location.column - 1,
+            )?;
+        }
+
+        if let Some(ref help) = self.help {
+            write!(f, "\n{:gutter$} = help: {}", "", help, gutter = gutter)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(error: Error) -> Self {
+        Diagnostic::new(error.to_string())
+    }
+}
+
+/// Combine the diagnostics into a single error, reporting all of them at once.
+pub fn into_error(diagnostics: Vec<Diagnostic>) -> Error {
+    let count = diagnostics.len();
+    let mut description = diagnostics
+        .iter()
+        .map(Diagnostic::to_string)
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if count > 1 {
+        description = format!(
+            "{} errors found in the configuration:\n\n{}",
+            count, description
+        );
+    }
+    Error::new(description)
+}
+
+/// Find the candidate most similar to the name, if any is similar enough.
+pub fn suggest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::jaro_winkler(name, candidate), candidate))
+        .filter(|&(similarity, _)| similarity >= SUGGESTION_THRESHOLD)
+        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(_, candidate)| candidate)
+}
+
+/// State of a collection while scanning the YAML events.
+enum Frame {
+    Mapping { expecting_key: bool },
+    Sequence,
+}
+
+/// Positions of all the mapping keys within a YAML file, identified by the
+/// path of keys leading to them.
+#[derive(Default)]
+struct Keys {
+    stack: Vec<Frame>,
+    path: Vec<String>,
+    found: Vec<(Vec<String>, Marker)>,
+}
+
+impl Keys {
+    fn scan(text: &str) -> Self {
+        let mut keys = Keys::default();
+        // Errors in the YAML itself are reported when parsing it, so whatever
+        // was found until then is enough here.
+        let _ = Parser::new(text.chars()).load(&mut keys, false);
+        keys
+    }
+
+    /// Mark the value of the current key (if within a mapping) as done.
+    fn value_done(&mut self) {
+        if let Some(Frame::Mapping {
+            ref mut expecting_key,
+        }) = self.stack.last_mut()
+        {
+            if !*expecting_key {
+                *expecting_key = true;
+                self.path.pop();
+            }
+        }
+    }
+
+    /// Find the key at the given path.
+    fn find(&self, path: &[&str]) -> Option<(Marker, usize)> {
+        self.found
+            .iter()
+            .find(|(p, _)| p.iter().map(String::as_str).eq(path.iter().cloned()))
+            .map(|(p, marker)| (*marker, p.last().map_or(1, |k| k.chars().count())))
+    }
+
+    /// Find the first key with the given name within the repository, at any
+    /// depth.  An empty key finds the repository itself.
+    ///
+    /// Repositories are either listed under `repositories`, or at the top
+    /// level in the legacy format.
+    fn find_within(&self, repository: &str, key: &str) -> Option<(Marker, usize)> {
+        self.found
+            .iter()
+            .find(|(p, _)| {
+                let within = match p.first().map(String::as_str) {
+                    Some("repositories") if p.get(1).map(String::as_str) == Some(repository) => {
+                        &p[2..]
+                    }
+                    Some(first) if first == repository => &p[1..],
+                    _ => return false,
+                };
+                if key.is_empty() {
+                    within.is_empty()
+                } else {
+                    within.last().map(String::as_str) == Some(key)
+                }
+            })
+            .map(|(p, marker)| (*marker, p.last().map_or(1, |k| k.chars().count())))
+    }
+}
+
+impl MarkedEventReceiver for Keys {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::MappingStart(_) => self.stack.push(Frame::Mapping {
+                expecting_key: true,
+            }),
+            Event::SequenceStart(_) => self.stack.push(Frame::Sequence),
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.value_done();
+            }
+            Event::Scalar(value, ..) => match self.stack.last_mut() {
+                Some(Frame::Mapping { expecting_key }) if *expecting_key => {
+                    *expecting_key = false;
+                    self.path.push(value);
+                    self.found.push((self.path.clone(), marker));
+                }
+                _ => self.value_done(),
+            },
+            Event::Alias(_) => self.value_done(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{suggest, Diagnostic};
+    use std::path::Path;
+
+    #[test]
+    fn diagnostics() {
+        let text = "version: 1\nrepositories:\n  foo:\n    source: /\n    exlcude: [/tmp]\n";
+        let diagnostic = Diagnostic::new("unknown option `exlcude`")
+            .repository("foo")
+            .key("exlcude")
+            .help("did you mean `exclude`?")
+            .locate_in(Path::new("/etc/duplicity-front.yml"), text);
+        assert_eq!(
+            diagnostic.to_string(),
+            "Error in repository foo: unknown option `exlcude`\n \
+             --> /etc/duplicity-front.yml:5:5\n  |\n5 |     exlcude: [/tmp]\n  |     ^^^^^^^\n  \
+             = help: did you mean `exclude`?"
+        );
+
+        assert_eq!(
+            suggest("exlcude", vec!["include", "exclude", "source"]),
+            Some("exclude")
+        );
+        assert_eq!(suggest("foo", vec!["include", "exclude"]), None);
+    }
+}
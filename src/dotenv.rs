@@ -0,0 +1,124 @@
+//! Parsing of environment files in the dotenv format.
+//!
+//! Each line is either blank, a comment starting with `#`, or an assignment
+//! `KEY=VALUE` optionally preceded by `export`.  Values may be quoted:
+//! single quotes are taken literally, while double quotes allow the escapes
+//! `\n`, `\"`, `\\` and `\$`.  Unquoted values extend to the end of the line
+//! (or to a ` #` comment), with surrounding whitespace removed.
+
+use error::Error;
+
+/// Check whether the name is a valid environment variable name.
+pub fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic())
+        && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Check that nothing but a comment follows a quoted value.
+fn end_of_value(after: &str) -> Result<(), String> {
+    let after = after.trim_start();
+    if after.is_empty() || after.starts_with('#') {
+        Ok(())
+    } else {
+        Err("unexpected characters after the closing quote".to_owned())
+    }
+}
+
+/// Parse the value of an assignment.
+fn parse_value(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if let Some(rest) = value.strip_prefix('\'') {
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| "missing closing single quote".to_owned())?;
+        return end_of_value(&rest[end + 1..]).map(|_| rest[..end].to_owned());
+    }
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut parsed = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return end_of_value(&rest[i + 1..]).map(|_| parsed),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => parsed.push('\n'),
+                    Some((_, c)) if "\"\\$".contains(c) => parsed.push(c),
+                    Some((_, c)) => return Err(format!("unknown escape '\\{}'", c)),
+                    None => break,
+                },
+                c => parsed.push(c),
+            }
+        }
+        return Err("missing closing double quote".to_owned());
+    }
+    let value = match value.find(" #") {
+        Some(i) => &value[..i],
+        None => value,
+    };
+    Ok(value.trim_end().to_owned())
+}
+
+/// Parse the contents of an environment file, returning the variables in the
+/// order they are set.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut vars = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| Error::new(format!("line {}: {}.", n + 1, reason));
+
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let eq = line
+            .find('=')
+            .ok_or_else(|| invalid("expected an assignment of the form KEY=VALUE"))?;
+        let name = line[..eq].trim();
+        if !is_valid_name(name) {
+            return Err(invalid(&format!("'{}' is not a valid variable name", name)));
+        }
+        let value = parse_value(&line[eq + 1..]).map_err(|e| invalid(&e))?;
+        vars.push((name.to_owned(), value));
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+
+    #[test]
+    fn dotenv() {
+        let vars = parse(
+            r#"
+# Credentials
+export AWS_ACCESS_KEY_ID=AKIA
+SECRET = 'a #literal'
+QUOTED="line\nbreak \"quoted\" \$HOME" # comment
+PLAIN=some value # comment
+EMPTY=
+"#,
+        )
+        .unwrap();
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vars,
+            pairs(&[
+                ("AWS_ACCESS_KEY_ID", "AKIA"),
+                ("SECRET", "a #literal"),
+                ("QUOTED", "line\nbreak \"quoted\" $HOME"),
+                ("PLAIN", "some value"),
+                ("EMPTY", ""),
+            ])
+        );
+
+        assert!(parse("1KEY=value").is_err());
+        assert!(parse("KEY").is_err());
+        assert!(parse("KEY=\"unterminated").is_err());
+        assert!(parse("KEY='a'b").is_err());
+    }
+}
@@ -1,6 +1,22 @@
 use std::error;
 use std::fmt;
 
+/// Broad category of an error.
+///
+/// Most errors are simply reported to the user, but some need to be treated
+/// differently (e.g. to decide whether to clean up after a failed backup).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Any error not covered by the other kinds.
+    Other,
+    /// A subprocess exceeded its maximum runtime and was terminated.
+    Timeout,
+    /// The user interrupted the program while a subprocess was running.
+    Interrupted,
+    /// The repository is locked by another instance of the program.
+    AlreadyRunning,
+}
+
 /// Error type for the crate.
 ///
 /// This is shared across a lot of different modules within the crate.  The
@@ -9,18 +25,31 @@ use std::fmt;
 /// self-contained without requiring additional debug information.
 #[derive(Debug)]
 pub struct Error {
+    kind: ErrorKind,
     description: String,
 }
 
 impl Error {
     pub fn new<S>(description: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Error::with_kind(ErrorKind::Other, description)
+    }
+
+    pub fn with_kind<S>(kind: ErrorKind, description: S) -> Self
     where
         S: Into<String>,
     {
         Error {
+            kind,
             description: description.into(),
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
 
 impl fmt::Display for Error {
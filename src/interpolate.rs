@@ -0,0 +1,253 @@
+//! Interpolation of variables within the options of a repository.
+//!
+//! Variables are written as `${name}` or `${name:argument}`, and `$$` is
+//! replaced with a literal `$`.  The following variables are built in:
+//!
+//! - `${env:VAR}`: the value of the environment variable `VAR`;
+//! - `${hostname}`: the host name of the machine, or that given with `--host`;
+//! - `${user}`: the name of the current user;
+//! - `${home}`: the home directory of the current user;
+//! - `${repo}`: the name of the repository;
+//! - `${date:FORMAT}`: the current date and time, formatted with `strftime`
+//!   (e.g. `${date:%Y}`).
+//!
+//! Any other name is looked up within the `vars` of the configuration.
+//!
+//! The secrets of a repository (its passphrases, the secrets of its backend
+//! and the values of `env`) are never interpolated, so that they are used
+//! exactly as written.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use error::Error;
+use libc;
+use log::warn;
+use repository;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+/// Names of the built-in variables, which cannot be redefined within `vars`.
+pub const BUILTINS: &[&str] = &["env", "hostname", "user", "home", "repo", "date"];
+
+/// Context in which the variables are expanded.
+pub struct Context<'a> {
+    repo: &'a str,
+    vars: &'a BTreeMap<String, String>,
+}
+
+/// Host name given with `--host`, used instead of that of the machine.
+static HOSTNAME: OnceLock<String> = OnceLock::new();
+
+/// Set the host name used for `${hostname}` and to select the repositories.
+pub fn set_hostname(name: &str) {
+    if HOSTNAME.set(name.to_owned()).is_err() {
+        warn!("The host name can only be set once.");
+    }
+}
+
+/// Get the host name of the machine, unless another was set with
+/// `set_hostname`.
+pub fn hostname() -> Result<String, Error> {
+    if let Some(name) = HOSTNAME.get() {
+        return Ok(name.clone());
+    }
+
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return Err(Error::new("Unable to determine the host name."));
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Get the name of the current user.
+pub fn user() -> Result<String, Error> {
+    if let Some(user) = env::var("USER").ok().filter(|u| !u.is_empty()) {
+        return Ok(user);
+    }
+
+    let passwd = unsafe { libc::getpwuid(libc::getuid()) };
+    if passwd.is_null() {
+        return Err(Error::new("Unable to determine the current user."));
+    }
+    let name = unsafe { CStr::from_ptr((*passwd).pw_name) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Format the current date and time, checking that the format is valid.
+fn date(format: &str) -> Result<String, Error> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(Error::new(format!("Invalid date format '{}'.", format)));
+    }
+    Ok(Local::now().format(format).to_string())
+}
+
+impl<'a> Context<'a> {
+    pub fn new(repo: &'a str, vars: &'a BTreeMap<String, String>) -> Self {
+        Context { repo, vars }
+    }
+
+    /// Look up the value of a variable.
+    fn lookup(&self, variable: &str) -> Result<String, Error> {
+        let (name, argument) = match variable.find(':') {
+            Some(i) => (&variable[..i], Some(&variable[i + 1..])),
+            None => (variable, None),
+        };
+
+        match (name, argument) {
+            ("env", Some(var)) => env::var(var)
+                .map_err(|_| Error::new(format!("Environment variable {} is not set.", var))),
+            ("hostname", None) => hostname(),
+            ("user", None) => user(),
+            ("home", None) => dirs::home_dir()
+                .map(|home| home.to_string_lossy().into_owned())
+                .ok_or_else(|| Error::new("Unable to locate the home directory.")),
+            ("repo", None) => Ok(self.repo.to_owned()),
+            ("date", Some(format)) => date(format),
+            (name, None) if !BUILTINS.contains(&name) => self
+                .vars
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::new(format!("Unknown variable '${{{}}}'.", variable))),
+            _ => Err(Error::new(format!(
+                "Invalid use of the variable '${{{}}}'.",
+                variable
+            ))),
+        }
+    }
+
+    /// Expand all the variables within the string.
+    ///
+    /// If `env_vars` is set, as for paths, the environment variables written
+    /// as `$VAR` are expanded as well.  A `$` which is not followed by a valid
+    /// variable name is left as is.
+    fn expand(&self, s: &str, env_vars: bool) -> Result<String, Error> {
+        let mut expanded = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find('$') {
+            expanded.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(r) = rest.strip_prefix('$') {
+                expanded.push('$');
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix('{') {
+                let end = r
+                    .find('}')
+                    .ok_or_else(|| Error::new(format!("Unterminated variable within '{}'.", s)))?;
+                expanded.push_str(&self.lookup(&r[..end])?);
+                rest = &r[end + 1..];
+            } else {
+                let len = if env_vars { env_var_len(rest) } else { 0 };
+                if len == 0 {
+                    expanded.push('$');
+                    continue;
+                }
+                let var = &rest[..len];
+                let value = env::var(var).map_err(|_| {
+                    Error::new(format!(
+                        "Environment variable {} used in path {} is not set.",
+                        var, s
+                    ))
+                })?;
+                expanded.push_str(&value);
+                rest = &rest[len..];
+            }
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Expand all the variables within the strings of the YAML value, as for
+    /// `expand`.
+    ///
+    /// Only values are expanded; the keys of mappings are left as is.
+    fn expand_value(&self, value: &Value, env_vars: bool) -> Result<Value, Error> {
+        Ok(match value {
+            Value::String(s) => Value::String(self.expand(s, env_vars)?),
+            Value::Sequence(seq) => Value::Sequence(
+                seq.iter()
+                    .map(|v| self.expand_value(v, env_vars))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Mapping(m) => {
+                let mut expanded = Mapping::new();
+                for (k, v) in m {
+                    expanded.insert(k.clone(), self.expand_value(v, env_vars)?);
+                }
+                Value::Mapping(expanded)
+            }
+            v => v.clone(),
+        })
+    }
+
+    /// Expand all the variables within the options of a repository, given as
+    /// YAML, except for its secrets.
+    ///
+    /// The environment variables written as `$VAR` are expanded within the
+    /// paths as well.
+    pub fn expand_repository(&self, repository: &Value) -> Result<Value, Error> {
+        repository::except_secrets(repository, |repository| match repository {
+            Value::Mapping(m) => {
+                let mut expanded = Mapping::new();
+                for (k, v) in m {
+                    let path = k
+                        .as_str()
+                        .is_some_and(|k| repository::PATH_OPTIONS.contains(&k));
+                    let v = self.expand_value(&v, path)?;
+                    expanded.insert(k, v);
+                }
+                Ok(Value::Mapping(expanded))
+            }
+            repository => self.expand_value(&repository, false),
+        })
+    }
+}
+
+/// Length of the name of the environment variable at the start of `s`, or 0
+/// if it does not start with a valid name.
+fn env_var_len(s: &str) -> usize {
+    s.char_indices()
+        .find(|&(j, c)| !(c == '_' || c.is_ascii_alphabetic() || (j > 0 && c.is_ascii_digit())))
+        .map_or(s.len(), |(j, _)| j)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Context;
+    use std::collections::BTreeMap;
+    use std::env;
+
+    #[test]
+    fn expand() {
+        let mut vars = BTreeMap::new();
+        vars.insert("nas".to_owned(), "sftp://nas/".to_owned());
+        let context = Context::new("foo", &vars);
+
+        assert_eq!(
+            context.expand("${nas}/backups/${repo}", false).unwrap(),
+            "sftp://nas//backups/foo"
+        );
+        assert_eq!(
+            context.expand("$${repo} costs $5", false).unwrap(),
+            "${repo} costs $5"
+        );
+        assert_eq!(context.expand("${date:%%}", false).unwrap(), "%");
+        assert!(context.expand("${missing}", false).is_err());
+        assert!(context.expand("${repo", false).is_err());
+        assert!(context.expand("${repo:x}", false).is_err());
+        assert!(context.expand("${env}", false).is_err());
+        assert!(context.expand("${date:%Q}", false).is_err());
+
+        let path = env::var("PATH").unwrap();
+        assert_eq!(context.expand("$PATH/x", false).unwrap(), "$PATH/x");
+        assert_eq!(
+            context.expand("$PATH/${repo}", true).unwrap(),
+            format!("{}/foo", path)
+        );
+        assert_eq!(context.expand("$$PATH/a$/b", true).unwrap(), "$PATH/a$/b");
+        assert!(context.expand("$DUPLICITY_FRONT_TEST_UNSET", true).is_err());
+    }
+}
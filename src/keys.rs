@@ -0,0 +1,689 @@
+//! Checks of the GPG keys used by a repository.
+//!
+//! Before a backup is uploaded, each of the keys given by `encrypt_key`,
+//! `hidden_encrypt_key`, `sign_key` and `encrypt_sign_key` is looked up with
+//! the local `gpg` (honouring `gpg_binary`, `gpg_options` and
+//! `encrypt_secret_keyring` as duplicity does) so that a missing or expired
+//! key is reported before any upload starts rather than halfway through.
+//!
+//! Each of these options takes a single key or a list of keys, given as key
+//! IDs or fingerprints as required by duplicity.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use error::Error;
+use lint::Severity;
+use repository::Repository;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use serde_yaml::Value;
+use std::fmt;
+use std::process::Command;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle};
+
+/// Number of days before a key expires from which a warning is given, unless
+/// set by `key_expiry_warning_days`.
+pub const DEFAULT_EXPIRY_WARNING_DAYS: u64 = 30;
+
+/// Options giving the keys used by duplicity.
+const OPTIONS: &[&str] = &[
+    "encrypt_key",
+    "hidden_encrypt_key",
+    "sign_key",
+    "encrypt_sign_key",
+];
+
+/// Check that the key is given as duplicity requires, that is as a key ID or
+/// fingerprint of 8, 16 or 40 hexadecimal digits, optionally preceded by
+/// `0x`.
+fn check_key(key: &str) -> Result<(), Error> {
+    let digits = key.strip_prefix("0x").unwrap_or(key);
+    if [8, 16, 40].contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(Error::new(format!(
+            "Invalid key '{}'; it must be a key ID or fingerprint of 8, 16 or 40 hexadecimal \
+             digits (e.g. 'AA0E73D2').",
+            key
+        )))
+    }
+}
+
+/// Keys given by one of the options, written either as a single key or as a
+/// list of keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyList(pub Vec<String>);
+
+impl KeyList {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, String> {
+        self.0.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a KeyList {
+    type Item = &'a String;
+    type IntoIter = ::std::slice::Iter<'a, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl Serialize for KeyList {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.0.as_slice() {
+            [key] => serializer.serialize_str(key),
+            keys => keys.serialize(serializer),
+        }
+    }
+}
+
+/// Visitor accepting a single key.
+///
+/// Keys made only of digits are read as integers unless quoted, so these are
+/// accepted as well.
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a key")
+    }
+
+    fn visit_str<E>(self, key: &str) -> Result<String, E>
+    where
+        E: de::Error,
+    {
+        check_key(key).map_err(E::custom)?;
+        Ok(key.to_owned())
+    }
+
+    fn visit_u64<E>(self, key: u64) -> Result<String, E>
+    where
+        E: de::Error,
+    {
+        self.visit_str(&key.to_string())
+    }
+
+    fn visit_i64<E>(self, key: i64) -> Result<String, E>
+    where
+        E: de::Error,
+    {
+        self.visit_str(&key.to_string())
+    }
+
+    fn visit_f64<E>(self, key: f64) -> Result<String, E>
+    where
+        E: de::Error,
+    {
+        Err(E::custom(format!(
+            "Invalid key {}; a key which can be read as a number must be quoted.",
+            key
+        )))
+    }
+}
+
+/// A single key within a list of keys.
+///
+/// As there is no list to tell apart, the key is read as a string so that
+/// its text is kept as written (e.g. `0xAA0E73D2` is not read as an integer).
+struct Key(String);
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(KeyVisitor).map(Key)
+    }
+}
+
+/// Visitor accepting a single key, a list of keys, or nothing at all.
+struct KeyListVisitor;
+
+impl<'de> Visitor<'de> for KeyListVisitor {
+    type Value = KeyList;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a key or a list of keys")
+    }
+
+    fn visit_str<E>(self, key: &str) -> Result<KeyList, E>
+    where
+        E: de::Error,
+    {
+        KeyVisitor.visit_str(key).map(|key| KeyList(vec![key]))
+    }
+
+    fn visit_u64<E>(self, key: u64) -> Result<KeyList, E>
+    where
+        E: de::Error,
+    {
+        KeyVisitor.visit_u64(key).map(|key| KeyList(vec![key]))
+    }
+
+    fn visit_i64<E>(self, key: i64) -> Result<KeyList, E>
+    where
+        E: de::Error,
+    {
+        KeyVisitor.visit_i64(key).map(|key| KeyList(vec![key]))
+    }
+
+    fn visit_f64<E>(self, key: f64) -> Result<KeyList, E>
+    where
+        E: de::Error,
+    {
+        KeyVisitor.visit_f64(key).map(|key| KeyList(vec![key]))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<KeyList, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut keys = Vec::new();
+        while let Some(Key(key)) = seq.next_element()? {
+            keys.push(key);
+        }
+        Ok(KeyList(keys))
+    }
+
+    fn visit_unit<E>(self) -> Result<KeyList, E>
+    where
+        E: de::Error,
+    {
+        Ok(KeyList::default())
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyList {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(KeyListVisitor)
+    }
+}
+
+/// Step within a YAML document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// Position within a collection of a YAML document.
+enum Frame {
+    Mapping { key: String, expecting_key: bool },
+    Sequence { index: usize },
+}
+
+/// Text of the plain scalars given for the key options, identified by the
+/// steps leading to them.
+#[derive(Default)]
+struct KeyText {
+    stack: Vec<Frame>,
+    found: Vec<(Vec<Step>, String)>,
+}
+
+impl KeyText {
+    /// Steps leading to the current value.
+    fn path(&self) -> Vec<Step> {
+        self.stack
+            .iter()
+            .map(|frame| match *frame {
+                Frame::Mapping { ref key, .. } => Step::Key(key.clone()),
+                Frame::Sequence { index } => Step::Index(index),
+            })
+            .collect()
+    }
+
+    /// Whether the current value is that of one of the key options (possibly
+    /// with a `+` to extend the inherited keys), or within its list.
+    fn within_option(&self) -> bool {
+        let path = self.path();
+        let option = match path.as_slice() {
+            [.., Step::Key(option)] | [.., Step::Key(option), Step::Index(_)] => option,
+            _ => return false,
+        };
+        OPTIONS.contains(&option.trim_end_matches('+'))
+    }
+
+    /// Move on from the current value.
+    fn value_done(&mut self) {
+        match self.stack.last_mut() {
+            Some(Frame::Mapping { expecting_key, .. }) => *expecting_key = true,
+            Some(Frame::Sequence { index }) => *index += 1,
+            None => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for KeyText {
+    fn on_event(&mut self, event: Event, _: Marker) {
+        match event {
+            Event::MappingStart(_) => self.stack.push(Frame::Mapping {
+                key: String::new(),
+                expecting_key: true,
+            }),
+            Event::SequenceStart(_) => self.stack.push(Frame::Sequence { index: 0 }),
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.value_done();
+            }
+            Event::Scalar(text, style, ..) => match self.stack.last_mut() {
+                Some(Frame::Mapping { key, expecting_key }) if *expecting_key => {
+                    *key = text;
+                    *expecting_key = false;
+                }
+                _ => {
+                    if style == TScalarStyle::Plain && self.within_option() {
+                        self.found.push((self.path(), text));
+                    }
+                    self.value_done();
+                }
+            },
+            Event::Alias(_) => self.value_done(),
+            _ => {}
+        }
+    }
+}
+
+/// Restore the text of the keys which were read as numbers.
+///
+/// Unquoted keys such as `12345678` are read as integers, and some such as
+/// `1234E567` as floats, losing the key as written; these are replaced by the
+/// text given within the YAML document from which `value` was read.
+pub fn preserve_text(text: &str, value: &mut Value) {
+    let mut keys = KeyText::default();
+    // Errors in the YAML itself are reported when parsing it.
+    let _ = Parser::new(text.chars()).load(&mut keys, false);
+
+    for (path, key) in keys.found {
+        let mut current = Some(&mut *value);
+        for step in &path {
+            current = match (current, step) {
+                (Some(Value::Mapping(mapping)), Step::Key(k)) => {
+                    mapping.get_mut(&Value::from(k.as_str()))
+                }
+                (Some(Value::Sequence(seq)), &Step::Index(i)) => seq.get_mut(i),
+                _ => None,
+            };
+        }
+        if let Some(current) = current {
+            if let Value::Number(_) = *current {
+                *current = Value::String(key);
+            }
+        }
+    }
+}
+
+/// Public key as listed by `gpg --list-keys`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub fingerprint: String,
+    pub revoked: bool,
+    pub expired: bool,
+    pub expires: Option<DateTime<Utc>>,
+    /// Capabilities usable for the key as a whole (e.g. `E` to encrypt and `S`
+    /// to sign).
+    pub capabilities: String,
+}
+
+/// Status of a key given by one of the options of a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStatus {
+    pub option: &'static str,
+    pub key: String,
+    pub public: Option<PublicKey>,
+    /// Whether the secret key is available, which is needed to sign, and to
+    /// restore or verify an encrypted backup.
+    pub secret: bool,
+}
+
+impl KeyStatus {
+    /// Whether the option uses the key to encrypt and to sign respectively.
+    fn usage(&self) -> (bool, bool) {
+        match self.option {
+            "sign_key" => (false, true),
+            "encrypt_sign_key" => (true, true),
+            _ => (true, false),
+        }
+    }
+
+    /// Find the problems with the key as of `now`, warning about keys which
+    /// expire within `window`.
+    pub fn problems(&self, now: DateTime<Utc>, window: Duration) -> Vec<(Severity, String)> {
+        let mut problems = Vec::new();
+        let public = match self.public {
+            Some(ref public) => public,
+            None => {
+                problems.push((
+                    Severity::Error,
+                    "the public key was not found in the keyring.".to_owned(),
+                ));
+                return problems;
+            }
+        };
+        let (encrypt, sign) = self.usage();
+
+        if public.revoked {
+            problems.push((Severity::Error, "the key has been revoked.".to_owned()));
+        }
+        match public.expires {
+            Some(expires) if public.expired || expires <= now => problems.push((
+                Severity::Error,
+                format!("the key expired on {}.", expires.format("%Y-%m-%d")),
+            )),
+            None if public.expired => {
+                problems.push((Severity::Error, "the key has expired.".to_owned()))
+            }
+            Some(expires) if expires - now <= window => problems.push((
+                Severity::Warning,
+                format!(
+                    "the key expires in {} days, on {}.",
+                    (expires - now).num_days(),
+                    expires.format("%Y-%m-%d")
+                ),
+            )),
+            _ => {}
+        }
+        if !public.revoked && !public.expired {
+            if encrypt && !public.capabilities.contains('E') {
+                problems.push((
+                    Severity::Error,
+                    "the key has no usable subkey to encrypt with.".to_owned(),
+                ));
+            }
+            if sign && !public.capabilities.contains('S') {
+                problems.push((
+                    Severity::Error,
+                    "the key has no usable subkey to sign with.".to_owned(),
+                ));
+            }
+        }
+        if sign && !self.secret {
+            problems.push((
+                Severity::Error,
+                "the secret key needed to sign the backup is not available.".to_owned(),
+            ));
+        }
+
+        problems
+    }
+}
+
+impl fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: ", self.option, self.key)?;
+        let public = match self.public {
+            Some(ref public) => public,
+            None => return write!(f, "not found"),
+        };
+        write!(f, "{}", public.fingerprint)?;
+        match public.expires {
+            Some(expires) => write!(f, ", expires {}", expires.format("%Y-%m-%d"))?,
+            None => write!(f, ", does not expire")?,
+        }
+        if self.secret {
+            write!(f, ", secret key available")
+        } else {
+            write!(f, ", secret key not available")
+        }
+    }
+}
+
+/// Parse a field of `gpg --with-colons` holding seconds since the epoch.
+fn parse_timestamp(field: &str) -> Option<DateTime<Utc>> {
+    field
+        .parse()
+        .ok()
+        .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single())
+}
+
+/// Parse the first public key listed by `gpg --with-colons --list-keys`.
+fn parse_public_key(output: &str) -> Option<PublicKey> {
+    let mut key: Option<PublicKey> = None;
+    let mut previous = "";
+    for line in output.lines() {
+        let fields: Vec<_> = line.split(':').collect();
+        let field = |i: usize| fields.get(i).cloned().unwrap_or("");
+        match (field(0), key.as_mut()) {
+            ("pub", None) => {
+                key = Some(PublicKey {
+                    fingerprint: field(4).to_owned(),
+                    revoked: field(1) == "r",
+                    expired: field(1) == "e",
+                    expires: parse_timestamp(field(6)),
+                    capabilities: field(11).chars().filter(char::is_ascii_uppercase).collect(),
+                })
+            }
+            // The fingerprints following the subkeys are not those of the key.
+            ("fpr", Some(key)) if previous == "pub" => key.fingerprint = field(9).to_owned(),
+            ("pub", Some(_)) => break,
+            _ => {}
+        }
+        previous = fields[0];
+    }
+    key
+}
+
+/// Check whether `gpg --with-colons --list-secret-keys` lists a secret key
+/// which is actually available (as opposed to a stub).
+fn has_secret_key(output: &str) -> bool {
+    output.lines().any(|line| {
+        let fields: Vec<_> = line.split(':').collect();
+        fields[0] == "sec" && fields.get(14) != Some(&"#")
+    })
+}
+
+/// Arguments used to list keys in a format which can be parsed.
+const LIST_ARGS: &[&str] = &["--batch", "--with-colons", "--fixed-list-mode"];
+
+/// Construct a gpg command as run by duplicity for the repository, that is
+/// with its `gpg_binary` and `gpg_options`, and its `encrypt_secret_keyring`
+/// if the command uses the secret keys.
+pub fn gpg_cmd(repository: &Repository, secret: bool, args: &[&str]) -> Command {
+    let mut argv = repository.construct_sudo();
+    argv.push(
+        repository
+            .gpg_binary
+            .as_ref()
+            .map_or_else(|| "gpg".to_owned(), |p| p.to_string()),
+    );
+    if let Some(ref options) = repository.gpg_options {
+        argv.extend(options.split_whitespace().map(str::to_owned));
+    }
+    if let (Some(keyring), true) = (&repository.encrypt_secret_keyring, secret) {
+        argv.push("--secret-keyring".into());
+        argv.push(keyring.to_string());
+    }
+    argv.extend(args.iter().map(|&arg| arg.to_owned()));
+
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]);
+    cmd.envs(repository.environment());
+    cmd
+}
+
+/// Messages with which gpg reports that the key listed is not found.
+const NOT_FOUND: &[&str] = &["No public key", "No secret key", "not found"];
+
+/// Run the gpg command, returning its standard output.
+///
+/// gpg exits with an error when the key is not found, which is reported
+/// through the (empty) listing instead.  Any other failure is an error.
+fn run_gpg(mut cmd: Command) -> Result<String, Error> {
+    let output = cmd
+        .env("LC_ALL", "C")
+        .output()
+        .map_err(|e| Error::new(format!("Error when running gpg: {}", e)))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if NOT_FOUND.iter().any(|message| stderr.contains(message)) {
+        Ok(stdout)
+    } else {
+        Err(Error::new(format!(
+            "gpg failed with {}: {}",
+            output.status,
+            stderr.trim()
+        )))
+    }
+}
+
+/// Look up each of the keys of the repository.
+pub fn check(repository: &Repository) -> Result<Vec<KeyStatus>, Error> {
+    let mut statuses = Vec::new();
+    for (option, key) in repository.keys() {
+        let list = |secret: bool, command: &str| {
+            let args: Vec<_> = LIST_ARGS
+                .iter()
+                .cloned()
+                .chain(vec![command, key])
+                .collect();
+            run_gpg(gpg_cmd(repository, secret, &args))
+        };
+        let public = parse_public_key(&list(false, "--list-keys")?);
+        let secret = has_secret_key(&list(true, "--list-secret-keys")?);
+        statuses.push(KeyStatus {
+            option,
+            key: key.to_owned(),
+            public,
+            secret,
+        });
+    }
+    Ok(statuses)
+}
+
+/// Window within which the expiry of a key is warned about.
+pub fn expiry_window(repository: &Repository) -> Duration {
+    let days = repository
+        .key_expiry_warning_days
+        .unwrap_or(DEFAULT_EXPIRY_WARNING_DAYS);
+    Duration::days(days as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has_secret_key, parse_public_key, preserve_text, run_gpg, KeyList, KeyStatus};
+    use chrono::{Duration, TimeZone, Utc};
+    use lint::Severity;
+    use serde_yaml;
+    use std::process::Command;
+
+    #[test]
+    fn key_lists() {
+        let keys = |yaml: &str| serde_yaml::from_str::<KeyList>(yaml);
+        assert_eq!(
+            keys("AA0E73D2").unwrap(),
+            KeyList(vec!["AA0E73D2".to_owned()])
+        );
+        assert_eq!(
+            keys("[0xAA0E73D2AA0E73D2, 0123456789ABCDEF01231234567890ABCDEF0123]")
+                .unwrap()
+                .0
+                .len(),
+            2
+        );
+        assert!(keys("~").unwrap().is_empty());
+        assert!(keys("alice@example.com").is_err());
+        assert!(keys("[AA0E73D2, AA0E73]").is_err());
+        assert_eq!(
+            keys("12345678").unwrap(),
+            KeyList(vec!["12345678".to_owned()])
+        );
+        assert!(keys("1234E567").is_err());
+
+        let text = "encrypt_key: 1234E567\nsign_key+: [12345678, '1234E567']\n";
+        let mut value = serde_yaml::from_str(text).unwrap();
+        preserve_text(text, &mut value);
+        let key = |option: &str| serde_yaml::from_value::<KeyList>(value[option].clone());
+        assert_eq!(
+            key("encrypt_key").unwrap(),
+            KeyList(vec!["1234E567".to_owned()])
+        );
+        assert_eq!(
+            key("sign_key+").unwrap(),
+            KeyList(vec!["12345678".to_owned(), "1234E567".to_owned()])
+        );
+
+        let single = serde_yaml::to_string(&KeyList(vec!["AA0E73D2".to_owned()])).unwrap();
+        assert_eq!(single, "---\nAA0E73D2");
+    }
+
+    #[test]
+    fn keys() {
+        let listing = "\
+tru::1:1700000000:0:3:1:5
+pub:u:255:22:1234567890ABCDEF:1600000000:1800000000::u:::scESC:::::ed25519:::0:
+fpr:::::::::0123456789ABCDEF0123456789ABCDEF01234567:
+uid:u::::1600000000::HASH::Alice <alice@example.com>::::::::::0:
+sub:u:255:18:FEDCBA0987654321:1600000000:1800000000:::::e:::::cv25519::
+fpr:::::::::FFFFFFFFFFFFFFFFFFFFFFFFFEDCBA0987654321:
+";
+        let public = parse_public_key(listing).unwrap();
+        assert_eq!(
+            public.fingerprint,
+            "0123456789ABCDEF0123456789ABCDEF01234567"
+        );
+        assert_eq!(public.capabilities, "ESC");
+        assert_eq!(
+            public.expires,
+            Some(Utc.timestamp_opt(1_800_000_000, 0).unwrap())
+        );
+        assert!(parse_public_key("").is_none());
+
+        assert!(has_secret_key(
+            "sec:u:255:22:1234567890ABCDEF:1600000000:::u:::scESC:::+:::23::0:"
+        ));
+        assert!(!has_secret_key(
+            "sec:u:255:22:1234567890ABCDEF:1600000000:::u:::scESC:::#:::23::0:"
+        ));
+        assert!(!has_secret_key(""));
+
+        let mut status = KeyStatus {
+            option: "sign_key",
+            key: "1234567890ABCDEF".to_owned(),
+            public: Some(public),
+            secret: false,
+        };
+        let window = Duration::days(30);
+        let severities = |status: &KeyStatus, now| {
+            status
+                .problems(Utc.timestamp_opt(now, 0).unwrap(), window)
+                .into_iter()
+                .map(|(severity, _)| severity)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(severities(&status, 1_700_000_000), [Severity::Error]);
+        status.secret = true;
+        assert!(severities(&status, 1_700_000_000).is_empty());
+        assert_eq!(severities(&status, 1_799_000_000), [Severity::Warning]);
+        assert_eq!(severities(&status, 1_800_000_000), [Severity::Error]);
+        status.public = None;
+        assert_eq!(severities(&status, 1_700_000_000), [Severity::Error]);
+
+        let gpg = |script: &str| {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(script);
+            run_gpg(cmd)
+        };
+        assert_eq!(gpg("echo listing").unwrap(), "listing\n");
+        assert_eq!(
+            gpg("echo 'gpg: error reading key: No public key' >&2; exit 2").unwrap(),
+            ""
+        );
+        assert!(gpg("echo 'gpg: keydb_search failed: Permission denied' >&2; exit 2").is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! Linting of the configuration.
+//!
+//! The lints go beyond the sanity checks of `Repository::check` and look for
+//! layouts which are valid but likely to be mistakes, possibly across
+//! several repositories.  Each finding applies to a single repository, and
+//! can be suppressed by listing the lint within its `allow_lints`.
+
+use config::Config;
+use remote::{Backend, Remote};
+use repository::Repository;
+use std::fmt;
+use std::path::Path;
+use time::DuplicityTime;
+
+/// Severity of a finding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The lints which are checked.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lint {
+    SharedRemote,
+    RemoteInsideSource,
+    OverlappingSources,
+    UnencryptedRemote,
+    RetentionKeepsNothing,
+    UnreachableExclude,
+    HomeRelativeRemote,
+}
+
+impl Lint {
+    pub fn severity(self) -> Severity {
+        match self {
+            Lint::SharedRemote | Lint::RemoteInsideSource | Lint::RetentionKeepsNothing => {
+                Severity::Error
+            }
+            Lint::OverlappingSources
+            | Lint::UnencryptedRemote
+            | Lint::UnreachableExclude
+            | Lint::HomeRelativeRemote => Severity::Warning,
+        }
+    }
+
+    /// Explanation of why the lint matters.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            Lint::SharedRemote => {
+                "Duplicity expects a single backup chain per remote; two repositories \
+                 writing to the same remote corrupt each other's chains."
+            }
+            Lint::RemoteInsideSource => {
+                "The backup is stored within the files being backed up, so that each \
+                 backup contains the previous ones and grows without bound."
+            }
+            Lint::OverlappingSources => {
+                "Without distinct names, duplicity may share its archive directory (and \
+                 thus its cached signatures) between the repositories."
+            }
+            Lint::UnencryptedRemote => {
+                "The backup is stored unencrypted on a machine other than this one."
+            }
+            Lint::RetentionKeepsNothing => {
+                "The retention options remove every backup, including the one just made."
+            }
+            Lint::UnreachableExclude => {
+                "Duplicity uses the first matching file selection option, and the \
+                 'include' options are given before the 'exclude' options, so the \
+                 exclusion never applies."
+            }
+            Lint::HomeRelativeRemote => {
+                "With the sftp, scp and ssh backends, a single slash after the host makes \
+                 the path relative to the home directory; an absolute path needs two \
+                 slashes (e.g. 'sftp://host//srv/backup')."
+            }
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Lint::SharedRemote => "shared-remote",
+            Lint::RemoteInsideSource => "remote-inside-source",
+            Lint::OverlappingSources => "overlapping-sources",
+            Lint::UnencryptedRemote => "unencrypted-remote",
+            Lint::RetentionKeepsNothing => "retention-keeps-nothing",
+            Lint::UnreachableExclude => "unreachable-exclude",
+            Lint::HomeRelativeRemote => "home-relative-remote",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A problem found within a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub lint: Lint,
+    pub repository: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{}] {}: {}\n    {}",
+            self.lint.severity(),
+            self.lint,
+            self.repository,
+            self.message,
+            self.lint.explanation()
+        )
+    }
+}
+
+/// Top-level directories which are unlikely to exist within a home
+/// directory, so that a remote path starting with one of them was most likely
+/// meant to be absolute.
+const ROOT_DIRECTORIES: &[&str] = &[
+    "backup", "backups", "data", "home", "media", "mnt", "opt", "root", "srv", "tmp", "var",
+];
+
+/// Check whether the path contains any glob special characters.
+fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Check whether the file selection pattern matches the path or any of its
+/// parent directories.
+fn selects(pattern: &str, path: &Path) -> bool {
+    let glob = if is_pattern(pattern) {
+        glob::Pattern::new(pattern).ok()
+    } else {
+        None
+    };
+    path.ancestors().any(|p| match glob {
+        Some(ref glob) => glob.matches_path(p),
+        None => p == Path::new(pattern),
+    })
+}
+
+/// Lints which apply to a single repository.
+fn lint_repository(
+    name: &str,
+    repository: &Repository,
+    source: &Path,
+    remote: &Remote,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut push = |lint, message| {
+        findings.push(Finding {
+            lint,
+            repository: name.to_owned(),
+            message,
+        })
+    };
+
+    if let Some(path) = remote.local_path() {
+        let excluded = repository
+            .exclude
+            .iter()
+            .any(|exclude| selects(exclude, path));
+        if path.starts_with(source) && !excluded {
+            push(
+                Lint::RemoteInsideSource,
+                format!(
+                    "The remote {} is within the source {} and is not excluded.",
+                    remote,
+                    source.display()
+                ),
+            );
+        }
+    } else if repository.no_encryption {
+        push(
+            Lint::UnencryptedRemote,
+            format!(
+                "'no_encryption' is set for the remote {}.",
+                remote.redacted()
+            ),
+        );
+    }
+
+    if remote.backend == Backend::Ssh && !remote.path.starts_with("//") {
+        let first = remote.path.trim_start_matches('/').split('/').next();
+        if first.is_some_and(|first| ROOT_DIRECTORIES.contains(&first)) {
+            push(
+                Lint::HomeRelativeRemote,
+                format!(
+                    "The path {} of the remote {} is relative to the home directory.",
+                    remote.path,
+                    remote.redacted()
+                ),
+            );
+        }
+    }
+
+    // Note that `remove_all_inc_of_but_n_full: 0` still keeps the full
+    // backups, so it is not considered here.
+    if repository.remove_all_but_n_full == Some(0)
+        || repository
+            .remove_older_than
+            .as_ref()
+            .is_some_and(DuplicityTime::is_now)
+    {
+        push(
+            Lint::RetentionKeepsNothing,
+            "The retention options do not keep any backup.".to_owned(),
+        );
+    }
+
+    for exclude in &repository.exclude {
+        if is_pattern(exclude) {
+            continue;
+        }
+        if let Some(include) = repository
+            .include
+            .iter()
+            .find(|include| selects(include, Path::new(exclude)))
+        {
+            push(
+                Lint::UnreachableExclude,
+                format!(
+                    "The exclusion {} is already matched by the inclusion {}.",
+                    exclude, include
+                ),
+            );
+        }
+    }
+
+    findings
+}
+
+/// Lint all the repositories within the configuration.
+///
+/// Findings are sorted by repository, and those suppressed through
+/// `allow_lints` are omitted.
+pub fn lint(config: &Config) -> Vec<Finding> {
+    let mut leaves: Vec<_> = config
+        .repositories
+        .iter()
+        .filter_map(
+            |(name, repository)| match (&repository.source, &repository.remote) {
+                (Some(source), Some(remote)) => Some((name, repository, source.as_path(), remote)),
+                _ => None,
+            },
+        )
+        .collect();
+    leaves.sort_by_key(|&(name, _, _, _)| name);
+
+    let mut findings = Vec::new();
+    for &(name, repository, source, remote) in &leaves {
+        findings.extend(lint_repository(name, repository, source, remote));
+
+        for &(other_name, other, other_source, other_remote) in &leaves {
+            if other_name == name {
+                continue;
+            }
+
+            if remote.as_str().trim_end_matches('/') == other_remote.as_str().trim_end_matches('/')
+            {
+                findings.push(Finding {
+                    lint: Lint::SharedRemote,
+                    repository: name.clone(),
+                    message: format!(
+                        "The remote {} is also used by {}.",
+                        remote.redacted(),
+                        other_name
+                    ),
+                });
+            }
+
+            let distinct_names = match (&repository.name, &other.name) {
+                (Some(a), Some(b)) => a != b,
+                _ => false,
+            };
+            if (source.starts_with(other_source) || other_source.starts_with(source))
+                && !distinct_names
+            {
+                findings.push(Finding {
+                    lint: Lint::OverlappingSources,
+                    repository: name.clone(),
+                    message: format!(
+                        "The source {} overlaps with the source {} of {}, and the two \
+                         repositories do not have distinct names.",
+                        source.display(),
+                        other_source.display(),
+                        other_name
+                    ),
+                });
+            }
+        }
+    }
+
+    findings.sort_by(|a, b| a.repository.cmp(&b.repository));
+    findings.retain(|finding| {
+        !config.repositories[&finding.repository]
+            .allow_lints
+            .contains(&finding.lint)
+    });
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lint, Lint};
+    use config::Config;
+
+    #[test]
+    fn lints() {
+        let config = Config::from_reader(
+            r#"
+version: 1
+repositories:
+  a:
+    source: /
+    remote: file:///backup/a
+    include: [/home]
+    exclude: [/home/cache, "**/tmp"]
+    remove_all_but_n_full: 0
+  b:
+    source: /usr
+    remote: sftp://host//backup/b
+    no_encryption: true
+    name: b
+  c:
+    source: /usr
+    remote: sftp://host//backup/b
+    name: c
+    allow_lints: [shared-remote]
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let findings: Vec<_> = lint(&config)
+            .into_iter()
+            .map(|f| (f.repository, f.lint))
+            .collect();
+        assert_eq!(
+            findings,
+            vec![
+                ("a".to_owned(), Lint::RemoteInsideSource),
+                ("a".to_owned(), Lint::RetentionKeepsNothing),
+                ("a".to_owned(), Lint::UnreachableExclude),
+                ("a".to_owned(), Lint::OverlappingSources),
+                ("a".to_owned(), Lint::OverlappingSources),
+                ("b".to_owned(), Lint::UnencryptedRemote),
+                ("b".to_owned(), Lint::OverlappingSources),
+                ("b".to_owned(), Lint::SharedRemote),
+                ("c".to_owned(), Lint::OverlappingSources),
+            ]
+        );
+
+        let config = Config::from_reader(
+            r#"
+version: 1
+repositories:
+  d:
+    source: /etc
+    remote: sftp://host/srv/d
+  e:
+    source: /usr
+    remote: sftp://host/backup-of-usr
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let findings: Vec<_> = lint(&config)
+            .into_iter()
+            .map(|f| (f.repository, f.lint))
+            .collect();
+        assert_eq!(findings, vec![("d".to_owned(), Lint::HomeRelativeRemote)]);
+    }
+}
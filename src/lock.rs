@@ -0,0 +1,215 @@
+//! Per-repository lock files.
+//!
+//! These prevent two instances of the front from running duplicity against
+//! the same repository at the same time.  The locks are advisory (using
+//! `flock`) and are stored in the XDG runtime directory, or the XDG state
+//! directory if there is no runtime directory.  Each lock file records the PID
+//! of the process holding it.
+
+use error::{Error, ErrorKind};
+use libc;
+use log::{debug, info, warn};
+use process;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::{env, path, thread, time};
+
+/// Interval between attempts to take a lock when waiting for it.
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// A lock held on a repository, which is released when dropped.
+pub struct Lock {
+    file: File,
+    path: path::PathBuf,
+}
+
+/// Directory in which the lock files are stored.
+fn lock_dir() -> Result<path::PathBuf, Error> {
+    dirs::runtime_dir()
+        .or_else(|| env::var_os("XDG_STATE_HOME").map(path::PathBuf::from))
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("state")))
+        .map(|dir| dir.join("duplicity-front").join("locks"))
+        .ok_or_else(|| Error::new("Unable to locate a directory in which to store lock files."))
+}
+
+/// File name of the lock for a repository.
+///
+/// Repository names may contain characters which are not valid within file
+/// names, so these are escaped.
+fn lock_file_name(name: &str) -> String {
+    let mut file_name = String::with_capacity(name.len() + 5);
+    for c in name.chars() {
+        match c {
+            '%' => file_name.push_str("%25"),
+            '/' => file_name.push_str("%2F"),
+            c => file_name.push(c),
+        }
+    }
+    file_name.push_str(".lock");
+    file_name
+}
+
+/// Check whether a process with the given PID is still running.
+fn is_running(pid: libc::pid_t) -> bool {
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Attempt to take an exclusive lock on the file without blocking.
+fn try_flock(file: &File) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+/// Read the PID recorded within the lock file, if any.
+fn recorded_pid(file: &mut File) -> Option<libc::pid_t> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+impl Lock {
+    /// Take the lock for the named repository.
+    ///
+    /// If the lock is already held by another process and `wait` is `false`,
+    /// an error of kind `AlreadyRunning` is returned.  Otherwise, this waits
+    /// until the lock is released (or the user interrupts the front).
+    pub fn acquire(name: &str, wait: bool) -> Result<Lock, Error> {
+        Lock::acquire_in(&lock_dir()?, name, wait)
+    }
+
+    /// Take the lock for the named repository, as for `acquire`, with the
+    /// lock file stored within the given directory.
+    fn acquire_in(dir: &path::Path, name: &str, wait: bool) -> Result<Lock, Error> {
+        fs::create_dir_all(dir).map_err(|e| {
+            Error::new(format!(
+                "Error when creating the lock directory {}: {}",
+                dir.display(),
+                e
+            ))
+        })?;
+
+        let path = dir.join(lock_file_name(name));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| {
+                Error::new(format!(
+                    "Error when opening the lock file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+
+        let mut waiting = false;
+        while !try_flock(&file) {
+            let holder = recorded_pid(&mut file);
+            if let Some(pid) = holder {
+                if !is_running(pid) {
+                    warn!(
+                        "Lock on repository {} is held, but its recorded process ({}) is no longer running.",
+                        name, pid
+                    );
+                }
+            }
+
+            if !wait {
+                return Err(Error::with_kind(
+                    ErrorKind::AlreadyRunning,
+                    match holder {
+                        Some(pid) => format!(
+                            "Repository {} is already in use by another process (PID {}).",
+                            name, pid
+                        ),
+                        None => {
+                            format!("Repository {} is already in use by another process.", name)
+                        }
+                    },
+                ));
+            }
+
+            if !waiting {
+                info!("Waiting for the lock on repository {}.", name);
+                waiting = true;
+            }
+            process::check_interrupted()?;
+            thread::sleep(RETRY_INTERVAL);
+        }
+
+        if let Some(pid) = recorded_pid(&mut file) {
+            if pid != unsafe { libc::getpid() } {
+                info!(
+                    "Removing stale lock on repository {} left by process {}.",
+                    name, pid
+                );
+            }
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", unsafe { libc::getpid() }))
+            .map_err(|e| {
+                Error::new(format!(
+                    "Error when writing to the lock file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+
+        debug!("Acquired lock {}.", path.display());
+        Ok(Lock { file, path })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // The file is left in place so that any process waiting on it still
+        // refers to the same file; only the recorded PID is cleared.  The
+        // lock itself is released once the file is closed.
+        if let Err(e) = self.file.set_len(0) {
+            warn!(
+                "Error when clearing the lock file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+        debug!("Released lock {}.", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lock_file_name, Lock};
+    use error::ErrorKind;
+    use std::{env, fs, process};
+
+    #[test]
+    fn file_names() {
+        assert_eq!(lock_file_name("home"), "home.lock");
+        assert_eq!(lock_file_name("home/docs"), "home%2Fdocs.lock");
+        assert_eq!(lock_file_name("100%/a"), "100%25%2Fa.lock");
+    }
+
+    #[test]
+    fn contention() {
+        let dir = env::temp_dir().join(format!("duplicity-front-{}-locks", process::id()));
+        let lock = Lock::acquire_in(&dir, "home/docs", false).unwrap();
+        let path = dir.join("home%2Fdocs.lock");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        let error = Lock::acquire_in(&dir, "home/docs", false).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AlreadyRunning);
+        assert!(Lock::acquire_in(&dir, "home", false).is_ok());
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(Lock::acquire_in(&dir, "home/docs", false).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+//! Paths within the configuration.
+//!
+//! Duplicity is not run through a shell, so paths are expanded by the front
+//! when the configuration is loaded:
+//!
+//! - `$VAR` is replaced with the value of the environment variable `VAR`,
+//!   along with the other variables (see the `interpolate` module);
+//! - a leading `~` is replaced with the home directory;
+//! - relative paths are resolved against the directory of the configuration
+//!   file in which the repository is defined.
+
+use error::Error;
+use std::ffi::OsStr;
+use std::{fmt, path};
+
+/// A path as specified within the configuration file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct ConfigPath(String);
+
+impl ConfigPath {
+    pub fn as_path(&self) -> &path::Path {
+        path::Path::new(&self.0)
+    }
+
+    /// Check whether this is the name of a command to be looked up within
+    /// `$PATH`, instead of a path.
+    pub fn is_command(&self) -> bool {
+        !self.0.contains('/') && !self.0.starts_with('~') && !self.0.starts_with('$')
+    }
+
+    /// Expand the leading `~` of the path, resolving it against `base` if it
+    /// is relative.
+    ///
+    /// The variables are expanded beforehand, along with those of the other
+    /// options.
+    pub fn resolve(&self, base: &path::Path) -> Result<ConfigPath, Error> {
+        let expanded = expand_tilde(&self.0)?;
+        // Collecting the components removes any `.` within the path.
+        let resolved: path::PathBuf = base.join(expanded).components().collect();
+        resolved
+            .to_str()
+            .map(|p| ConfigPath(p.to_owned()))
+            .ok_or_else(|| Error::new(format!("Path {} is not valid UTF-8.", resolved.display())))
+    }
+}
+
+impl fmt::Display for ConfigPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl AsRef<OsStr> for ConfigPath {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl<'a> From<&'a str> for ConfigPath {
+    fn from(s: &'a str) -> Self {
+        ConfigPath(s.to_owned())
+    }
+}
+
+/// Replace a leading `~` with the home directory.
+fn expand_tilde(s: &str) -> Result<String, Error> {
+    let rest = match s.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return Ok(s.to_owned()),
+    };
+    let home = dirs::home_dir().ok_or_else(|| {
+        Error::new(format!(
+            "Path {} starts with '~' but the home directory could not be located.",
+            s
+        ))
+    })?;
+    Ok(format!("{}{}", home.display(), rest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigPath;
+    use std::path;
+
+    #[test]
+    fn resolve() {
+        let base = path::Path::new("/etc/duplicity-front.d");
+        let home = dirs::home_dir().unwrap();
+        let resolve = |s: &str| ConfigPath::from(s).resolve(base).map(|p| p.to_string());
+
+        assert_eq!(resolve("/var/backup").unwrap(), "/var/backup");
+        assert_eq!(
+            resolve("./excludes.txt").unwrap(),
+            "/etc/duplicity-front.d/excludes.txt"
+        );
+        assert_eq!(resolve("~").unwrap(), home.display().to_string());
+        assert_eq!(
+            resolve("~/docs").unwrap(),
+            format!("{}/docs", home.display())
+        );
+        assert_eq!(resolve("~other").unwrap(), "/etc/duplicity-front.d/~other");
+        assert_eq!(resolve("/a$/b").unwrap(), "/a$/b");
+
+        assert!(ConfigPath::from("gpg2").is_command());
+        assert!(!ConfigPath::from("bin/gpg2").is_command());
+    }
+}
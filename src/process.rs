@@ -0,0 +1,294 @@
+//! Subprocess management.
+//!
+//! Duplicity is run in its own process group so that it (and anything it
+//! spawns, such as `ssh`) can be terminated as a whole when it exceeds its
+//! maximum runtime, or when the user interrupts the front.
+//!
+//! When run from a terminal, the subprocess' process group is made the
+//! foreground process group so that it can still prompt for passwords, and
+//! Ctrl-C is delivered to it directly by the terminal.  Otherwise, `SIGINT`
+//! and `SIGTERM` received by the front are forwarded to the process group.
+
+use error::{Error, ErrorKind};
+use libc;
+use log::{info, warn};
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time given to the process group to exit after `SIGTERM` before it is
+/// killed with `SIGKILL`.
+const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Interval at which the subprocess is polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Signal received by the front which has yet to be handled (0 if none).
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the signal handlers have been installed.
+static HANDLERS_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Deadline for the whole run, if any.
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Install the handlers for `SIGINT` and `SIGTERM` so that they can be
+/// forwarded to the running subprocess instead of leaving it orphaned.
+pub fn install_signal_handlers() {
+    if HANDLERS_INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Set the overall maximum runtime, measured from now.
+pub fn set_max_runtime(max_runtime: Duration) {
+    if DEADLINE.set(Instant::now() + max_runtime).is_err() {
+        warn!("The overall maximum runtime can only be set once.");
+    }
+}
+
+/// Check whether an interrupt has been received, returning an error if so.
+pub fn check_interrupted() -> Result<(), Error> {
+    match PENDING_SIGNAL.load(Ordering::SeqCst) {
+        0 => Ok(()),
+        _ => Err(Error::with_kind(
+            ErrorKind::Interrupted,
+            "Interrupted by the user.",
+        )),
+    }
+}
+
+/// Check whether standard input is a terminal.
+fn is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Make the specified process group the foreground process group of the
+/// terminal.
+///
+/// `SIGTTOU` is ignored for the duration of the call as otherwise a
+/// background process group attempting this would be stopped.
+fn set_foreground(pgid: libc::pid_t) -> io::Result<()> {
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        let result = libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::signal(libc::SIGTTOU, previous);
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Send a signal to the process group of the child.
+fn signal_group(child: &Child, signal: libc::c_int) {
+    let pgid = child.id() as libc::pid_t;
+    if unsafe { libc::kill(-pgid, signal) } != 0 {
+        warn!(
+            "Unable to send signal {} to process group {}.",
+            signal, pgid
+        );
+    }
+}
+
+/// Terminate the child's process group, first with the specified signal and
+/// then with `SIGKILL` if it has not exited after the grace period.
+fn terminate(child: &mut Child, signal: libc::c_int) {
+    signal_group(child, signal);
+
+    let start = Instant::now();
+    while start.elapsed() < GRACE_PERIOD {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    warn!(
+        "Subprocess did not exit within {} seconds; sending SIGKILL.",
+        GRACE_PERIOD.as_secs()
+    );
+    signal_group(child, libc::SIGKILL);
+    let _ = child.wait();
+}
+
+/// Quote the argument for display so that it can be pasted into a shell.
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Run the specified command and check that it exits correctly, or return an
+/// error as appropriate.
+///
+/// The command is terminated if it exceeds `max_runtime` or the overall
+/// deadline (whichever comes first), and interrupts received by the front
+/// are forwarded to it.
+pub fn run(cmd: &mut Command, max_runtime: Option<Duration>) -> Result<(), Error> {
+    let start = Instant::now();
+    let deadline = match (max_runtime.map(|d| start + d), DEADLINE.get()) {
+        (Some(a), Some(&b)) => Some(a.min(b)),
+        (a, b) => a.or_else(|| b.cloned()),
+    };
+
+    run_until(cmd, start, deadline)
+}
+
+/// Run the specified command as with `run`, except that the overall deadline
+/// is ignored.
+///
+/// This is intended for cleaning up after a subprocess was terminated because
+/// the overall deadline was reached.
+pub fn run_ignoring_deadline(
+    cmd: &mut Command,
+    max_runtime: Option<Duration>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    run_until(cmd, start, max_runtime.map(|d| start + d))
+}
+
+/// Run the specified command until it exits or the deadline is reached.
+fn run_until(cmd: &mut Command, start: Instant, deadline: Option<Instant>) -> Result<(), Error> {
+    run_within(cmd, start, deadline, is_terminal())
+}
+
+/// Run the specified command as with `run_until`, making its process group
+/// the foreground process group of the terminal if `terminal` is set.
+fn run_within(
+    cmd: &mut Command,
+    start: Instant,
+    deadline: Option<Instant>,
+    terminal: bool,
+) -> Result<(), Error> {
+    check_interrupted()?;
+    install_signal_handlers();
+
+    if deadline.is_some_and(|d| start >= d) {
+        return Err(Error::with_kind(
+            ErrorKind::Timeout,
+            "The overall maximum runtime was exceeded before the subprocess could be started.",
+        ));
+    }
+
+    cmd.process_group(0);
+    if terminal {
+        // The child takes over the terminal itself, so that there is no race
+        // between it reading from the terminal and the parent handing it over.
+        unsafe {
+            cmd.pre_exec(|| set_foreground(libc::getpid()));
+        }
+    }
+
+    info!("command: {:?}", cmd);
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| Error::new(format!("Error when spawning subprocess: {}", e)))?;
+
+    let result = wait(&mut child, start, deadline);
+
+    if terminal {
+        if let Err(e) = set_foreground(unsafe { libc::getpgrp() }) {
+            warn!("Unable to reclaim the terminal: {}", e);
+        }
+    }
+
+    result
+}
+
+/// Wait for the child to exit, terminating it if the deadline is reached or
+/// an interrupt is received.
+fn wait(child: &mut Child, start: Instant, deadline: Option<Instant>) -> Result<(), Error> {
+    loop {
+        let status = child
+            .try_wait()
+            .map_err(|e| Error::new(format!("Error when waiting subprocess: {}", e)))?;
+        if let Some(ecode) = status {
+            if ecode.signal() == Some(libc::SIGINT) {
+                PENDING_SIGNAL.store(libc::SIGINT, Ordering::SeqCst);
+            }
+            check_interrupted()?;
+            return if ecode.success() {
+                Ok(())
+            } else {
+                Err(Error::new("Subprocess encountered an error."))
+            };
+        }
+
+        let signal = PENDING_SIGNAL.load(Ordering::SeqCst);
+        if signal != 0 {
+            warn!("Forwarding signal {} to subprocess.", signal);
+            terminate(child, signal);
+            return check_interrupted();
+        }
+
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            warn!(
+                "Subprocess exceeded its maximum runtime after {} seconds; terminating it.",
+                start.elapsed().as_secs()
+            );
+            terminate(child, libc::SIGTERM);
+            return Err(Error::with_kind(
+                ErrorKind::Timeout,
+                format!(
+                    "Subprocess timed out after {} seconds.",
+                    start.elapsed().as_secs()
+                ),
+            ));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_within;
+    use error::ErrorKind;
+    use libc;
+    use std::process::{self, Command};
+    use std::time::{Duration, Instant};
+    use std::{env, fs};
+
+    #[test]
+    fn timeout() {
+        let pid_file = env::temp_dir().join(format!("duplicity-front-{}.pid", process::id()));
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!("echo $$ > '{}'; exec sleep 10", pid_file.display()));
+
+        // The terminal is never handed over, as the test binary would be
+        // stopped when taking it back from the background.
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(1);
+        let error = run_within(&mut cmd, start, Some(deadline), false).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let pid: libc::pid_t = fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        fs::remove_file(&pid_file).unwrap();
+        assert_ne!(unsafe { libc::kill(pid, 0) }, 0);
+    }
+}
@@ -0,0 +1,329 @@
+//! Resolution of repository options from defaults and templates.
+//!
+//! This operates on the raw YAML before the repositories are deserialized so
+//! that options can be merged field by field.  The semantics are as follows:
+//!
+//! - Mappings (such as `conditions`) are merged key by key.
+//! - Any other value replaces the inherited value, including lists.
+//! - A list whose key is suffixed with `+` (e.g. `exclude+`) is instead
+//!   appended to the inherited list, so that the inherited entries come first
+//!   and the order of the `include` and `exclude` filters is kept.  Within a
+//!   template, a list with nothing to append to is kept until the template is
+//!   applied, so that it is appended to the defaults.
+//!
+//! Repositories and templates can inherit from templates with `extends`,
+//! which takes either a single template name or a list of names.  Later
+//! templates in the list take precedence over earlier ones, and the entry
+//! itself takes precedence over all of them.
+
+use error::Error;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+
+/// Key used to inherit from templates.
+const EXTENDS: &str = "extends";
+
+/// Suffix marking a list which is to be appended to the inherited list.
+const APPEND_SUFFIX: char = '+';
+
+/// Set the value of a key within the mapping, keeping the position of the key
+/// if it already exists (unlike `Mapping::insert`).
+fn set(mapping: &mut Mapping, k: Value, v: Value) {
+    match mapping.get_mut(&k) {
+        Some(existing) => *existing = v,
+        None => {
+            mapping.insert(k, v);
+        }
+    }
+}
+
+/// Append the list given for `name` with the append suffix to the inherited
+/// value, if any.
+fn append(inherited: Option<&Value>, name: &str, value: Value) -> Result<Value, Error> {
+    let mut list = match inherited {
+        Some(Value::Sequence(list)) => list.clone(),
+        Some(Value::Null) | None => Vec::new(),
+        Some(_) => {
+            return Err(Error::new(format!(
+                "Unable to append to '{}' as the inherited value is not a list.",
+                name
+            )))
+        }
+    };
+    match value {
+        Value::Sequence(value) => list.extend(value),
+        _ => {
+            return Err(Error::new(format!(
+                "'{}{}' must be a list.",
+                name, APPEND_SUFFIX
+            )))
+        }
+    }
+    Ok(Value::Sequence(list))
+}
+
+/// Merge `value` on top of `base`.
+///
+/// See the module documentation for the semantics.  The result never contains
+/// keys with the append suffix.
+pub fn merge(base: &Value, value: Value) -> Result<Value, Error> {
+    let value = match value {
+        Value::Mapping(value) => value,
+        value => return Ok(value),
+    };
+    let mut merged = match base {
+        Value::Mapping(base) => base.clone(),
+        _ => Mapping::new(),
+    };
+
+    for (k, v) in value {
+        let append_to = k.as_str().and_then(|k| k.strip_suffix(APPEND_SUFFIX));
+        if let Some(name) = append_to {
+            let key = Value::from(name);
+            let list = append(merged.get(&key), name, v)?;
+            set(&mut merged, key, list);
+        } else {
+            let v = merge(merged.get(&k).unwrap_or(&Value::Null), v)?;
+            set(&mut merged, k, v);
+        }
+    }
+
+    Ok(Value::Mapping(merged))
+}
+
+/// Layer `value` on top of `base`, as for `merge` except that the keys with
+/// the append suffix are kept when there is nothing to append to within
+/// `base`.
+///
+/// The result can then be merged on top of another value, such as the
+/// defaults, to which these lists are appended.
+fn layer(base: Value, value: Value) -> Result<Value, Error> {
+    let value = match value {
+        Value::Mapping(value) => value,
+        value => return Ok(value),
+    };
+    let mut layered = match base {
+        Value::Mapping(base) => base,
+        _ => Mapping::new(),
+    };
+
+    for (k, v) in value {
+        let append_to = k.as_str().and_then(|k| k.strip_suffix(APPEND_SUFFIX));
+        if let Some(name) = append_to {
+            let key = Value::from(name);
+            if layered.contains_key(&key) {
+                let list = append(layered.get(&key), name, v)?;
+                set(&mut layered, key, list);
+            } else {
+                let list = append(layered.get(&k), name, v)?;
+                set(&mut layered, k, list);
+            }
+        } else {
+            // The value replaces anything which was to be appended to.
+            if let Some(name) = k.as_str() {
+                layered.remove(&Value::from(format!("{}{}", name, APPEND_SUFFIX)));
+            }
+            let v = layer(layered.get(&k).cloned().unwrap_or(Value::Null), v)?;
+            set(&mut layered, k, v);
+        }
+    }
+
+    Ok(Value::Mapping(layered))
+}
+
+/// Remove the `extends` key from the entry, returning the listed templates.
+fn take_extends(value: &mut Value) -> Result<Vec<String>, Error> {
+    let extends = match value {
+        Value::Mapping(m) => m.remove(&Value::from(EXTENDS)),
+        _ => None,
+    };
+
+    match extends {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::String(name)) => Ok(vec![name]),
+        Some(Value::Sequence(names)) => names
+            .into_iter()
+            .map(|name| match name {
+                Value::String(name) => Ok(name),
+                name => Err(Error::new(format!(
+                    "Template names within 'extends' must be strings, found {:?}.",
+                    name
+                ))),
+            })
+            .collect(),
+        Some(value) => Err(Error::new(format!(
+            "'extends' must be a template name or a list of template names, found {:?}.",
+            value
+        ))),
+    }
+}
+
+/// Resolves entries which inherit from templates.
+pub struct Resolver<'a> {
+    templates: &'a Mapping,
+    resolved: HashMap<String, Value>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(templates: &'a Mapping) -> Self {
+        Resolver {
+            templates,
+            resolved: HashMap::new(),
+        }
+    }
+
+    /// Fully resolve the named template, on top of `base`.
+    pub fn template(&mut self, base: &Value, name: &str) -> Result<Value, Error> {
+        let value = self.resolve_template(name, &mut Vec::new())?;
+        merge(base, value)
+    }
+
+    /// Resolve an entry, layering it on top of `base` and the templates it
+    /// extends.
+    pub fn resolve(&mut self, base: &Value, value: Value) -> Result<Value, Error> {
+        let value = self.inherit(value, &mut Vec::new())?;
+        merge(base, value)
+    }
+
+    fn resolve_template(&mut self, name: &str, stack: &mut Vec<String>) -> Result<Value, Error> {
+        if let Some(value) = self.resolved.get(name) {
+            return Ok(value.clone());
+        }
+
+        if stack.iter().any(|n| n == name) {
+            stack.push(name.to_owned());
+            return Err(Error::new(format!(
+                "Cycle detected within 'extends': {}.",
+                stack.join(" -> ")
+            )));
+        }
+
+        let value = self
+            .templates
+            .get(&Value::from(name))
+            .cloned()
+            .ok_or_else(|| Error::new(format!("Template {} could not be found.", name)))?;
+
+        stack.push(name.to_owned());
+        let value = self
+            .inherit(value, stack)
+            .map_err(|e| Error::new(format!("Error in template {}: {}", name, e)))?;
+        stack.pop();
+
+        self.resolved.insert(name.to_owned(), value.clone());
+        Ok(value)
+    }
+
+    /// Layer the entry on top of the templates it extends, keeping the lists
+    /// to be appended to the value on which it is finally merged.
+    fn inherit(&mut self, mut value: Value, stack: &mut Vec<String>) -> Result<Value, Error> {
+        let mut base = Value::Null;
+        for parent in take_extends(&mut value)? {
+            let parent = self.resolve_template(&parent, stack)?;
+            base = layer(base, parent)?;
+        }
+        layer(base, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Resolver;
+    use serde_yaml::{self, Mapping, Value};
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn extends() {
+        let templates: Mapping = serde_yaml::from_str(
+            r#"
+base:
+  exclude: [a, b]
+  volsize: 100
+  conditions:
+    max_load: 2.0
+remote:
+  extends: base
+  volsize: 200
+loop1:
+  extends: loop2
+loop2:
+  extends: [base, loop1]
+"#,
+        )
+        .unwrap();
+        let mut resolver = Resolver::new(&templates);
+
+        assert_eq!(
+            resolver
+                .resolve(
+                    &yaml("encrypt_key: ABCD"),
+                    yaml("{extends: remote, exclude+: [c], conditions: {weekdays: [mon]}}"),
+                )
+                .unwrap(),
+            yaml(
+                r#"
+encrypt_key: ABCD
+exclude: [a, b, c]
+volsize: 200
+conditions:
+  max_load: 2.0
+  weekdays: [mon]
+"#
+            )
+        );
+
+        assert_eq!(
+            resolver
+                .resolve(&Value::Null, yaml("{extends: [base], exclude: [c]}"))
+                .unwrap(),
+            yaml("{exclude: [c], volsize: 100, conditions: {max_load: 2.0}}")
+        );
+
+        assert!(resolver.template(&Value::Null, "loop1").is_err());
+        assert!(resolver.template(&Value::Null, "missing").is_err());
+        assert!(resolver
+            .resolve(&Value::Null, yaml("{extends: base, volsize+: [1]}"))
+            .is_err());
+    }
+
+    #[test]
+    fn append_to_defaults() {
+        let templates: Mapping = serde_yaml::from_str(
+            r#"
+home:
+  exclude+: [~/.cache]
+media:
+  extends: home
+  exclude+: [~/Videos]
+music:
+  exclude+: [~/Music]
+"#,
+        )
+        .unwrap();
+        let mut resolver = Resolver::new(&templates);
+        let defaults = yaml("exclude: [/tmp]");
+
+        assert_eq!(
+            resolver
+                .resolve(
+                    &defaults,
+                    yaml("{extends: [media, music], exclude+: [~/Downloads]}")
+                )
+                .unwrap(),
+            yaml("exclude: [/tmp, ~/.cache, ~/Videos, ~/Music, ~/Downloads]")
+        );
+        assert_eq!(
+            resolver.template(&defaults, "media").unwrap(),
+            yaml("exclude: [/tmp, ~/.cache, ~/Videos]")
+        );
+        assert_eq!(
+            resolver
+                .resolve(&defaults, yaml("{extends: home, exclude: [/srv]}"))
+                .unwrap(),
+            yaml("exclude: [/srv]")
+        );
+    }
+}
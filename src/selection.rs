@@ -0,0 +1,199 @@
+//! Selection of the repositories to run a subcommand over.
+//!
+//! Repositories are selected by name (or glob pattern), by tag, or all at
+//! once.  Groups are then expanded into the repositories they list, so that
+//! the selection is a single list of repositories which run duplicity, in
+//! the order they were selected and without duplicates.
+
+use config::Config;
+use error::Error;
+use std::collections::HashSet;
+
+/// A repository selected to be run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selected {
+    pub name: String,
+    /// Groups through which the repository was selected, outermost first.
+    pub groups: Vec<String>,
+}
+
+/// Check whether the name is a glob pattern rather than an exact name.
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// Names of all visible repositories, sorted.
+fn sorted_names(config: &Config) -> Vec<&String> {
+    let mut names: Vec<_> = config.repositories.keys().collect();
+    names.sort();
+    names
+}
+
+/// Select repositories.
+///
+/// Repositories are selected in the following order:
+///
+/// 1. those given in `names`, in the order given, with the matches of a glob
+///    pattern sorted by name;
+/// 2. those with any of the `tags`, sorted by name;
+/// 3. all repositories, sorted by name, if `all` is set.
+///
+/// Groups are then expanded, and only the first occurrence of each repository
+/// is kept.
+pub fn select(
+    config: &Config,
+    names: &[&str],
+    tags: &[&str],
+    all: bool,
+) -> Result<Vec<Selected>, Error> {
+    let mut roots: Vec<&str> = Vec::new();
+
+    for &name in names {
+        if is_pattern(name) {
+            let pattern = glob::Pattern::new(name)
+                .map_err(|e| Error::new(format!("Invalid repository pattern '{}': {}", name, e)))?;
+            let matches: Vec<_> = sorted_names(config)
+                .into_iter()
+                .filter(|n| pattern.matches(n))
+                .collect();
+            if matches.is_empty() {
+                return Err(Error::new(format!("No repository matches '{}'.", name)));
+            }
+            roots.extend(matches.into_iter().map(String::as_str));
+        } else if config.repositories.contains_key(name) {
+            roots.push(name);
+        } else if let Some(reason) = config.hidden.get(name) {
+            return Err(Error::new(format!(
+                "Repository {} is hidden: {}.",
+                name, reason
+            )));
+        } else {
+            return Err(Error::new(format!(
+                "Repository {} could not be loaded from the configuration.",
+                name
+            )));
+        }
+    }
+
+    for &tag in tags {
+        let tagged: Vec<_> = sorted_names(config)
+            .into_iter()
+            .filter(|n| config.repositories[*n].tags.iter().any(|t| t == tag))
+            .collect();
+        if tagged.is_empty() {
+            return Err(Error::new(format!("No repository has the tag '{}'.", tag)));
+        }
+        roots.extend(tagged.into_iter().map(String::as_str));
+    }
+
+    if all {
+        roots.extend(sorted_names(config).into_iter().map(String::as_str));
+    }
+
+    let mut selection = Vec::new();
+    let mut seen = HashSet::new();
+    for root in roots {
+        expand(config, root, &mut Vec::new(), &mut seen, &mut selection)?;
+    }
+    Ok(selection)
+}
+
+/// Expand the named repository into the repositories which run duplicity,
+/// appending those not yet seen to the selection.
+fn expand(
+    config: &Config,
+    name: &str,
+    groups: &mut Vec<String>,
+    seen: &mut HashSet<String>,
+    selection: &mut Vec<Selected>,
+) -> Result<(), Error> {
+    if groups.iter().any(|g| g == name) {
+        groups.push(name.to_owned());
+        return Err(Error::new(format!(
+            "Cycle detected within 'sub_repositories': {}.",
+            groups.join(" -> ")
+        )));
+    }
+
+    let repository = match config.repositories.get(name) {
+        Some(repository) => repository,
+        // Hidden repositories have already been removed from the groups.
+        None => return Ok(()),
+    };
+
+    // Groups whose sub-repositories are all hidden are left empty, so groups
+    // are told apart by the lack of a source instead.
+    if repository.source.is_none() {
+        groups.push(name.to_owned());
+        for sub_name in &repository.sub_repositories {
+            expand(config, sub_name, groups, seen, selection)?;
+        }
+        groups.pop();
+    } else if seen.insert(name.to_owned()) {
+        selection.push(Selected {
+            name: name.to_owned(),
+            groups: groups.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::select;
+    use config::Config;
+
+    #[test]
+    fn selection() {
+        let config = Config::from_reader(
+            r#"
+version: 1
+repositories:
+  all:
+    sub_repositories: [foo, "foo:bar"]
+  foo:
+    sub_repositories: ["foo:bar", "foo:baz"]
+  foo:bar:
+    source: /
+    remote: file:///backup/bar
+    tags: [nightly]
+  foo:baz:
+    source: /
+    remote: file:///backup/baz
+  qux:
+    source: /
+    remote: file:///backup/qux
+    tags: [nightly]
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let names = |names: &[&str], tags: &[&str], all: bool| {
+            select(&config, names, tags, all)
+                .map(|selection| selection.into_iter().map(|s| s.name).collect::<Vec<_>>())
+        };
+
+        assert_eq!(names(&["all"], &[], false).unwrap(), ["foo:bar", "foo:baz"]);
+        assert_eq!(
+            names(&["qux", "foo:*"], &[], false).unwrap(),
+            ["qux", "foo:bar", "foo:baz"]
+        );
+        assert_eq!(names(&[], &["nightly"], false).unwrap(), ["foo:bar", "qux"]);
+        assert_eq!(
+            names(&["qux"], &[], true).unwrap(),
+            ["qux", "foo:bar", "foo:baz"]
+        );
+        assert_eq!(
+            select(&config, &["foo:baz"], &[], false).unwrap()[0].groups,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            select(&config, &["all"], &[], false).unwrap()[1].groups,
+            ["all", "foo"]
+        );
+        assert!(names(&["missing"], &[], false).is_err());
+        assert!(names(&["bar:*"], &[], false).is_err());
+        assert!(names(&[], &["weekly"], false).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+//! Times and intervals as understood by duplicity.
+//!
+//! Duplicity accepts the following formats wherever a time is expected (see
+//! the "TIME FORMATS" section of its manual):
+//!
+//! - `now`;
+//! - an interval before now, made of integers each followed by one of the
+//!   units `s`, `m`, `h`, `D`, `W`, `M` or `Y` (e.g. `1D2h`);
+//! - a date and time in ISO 8601 format (e.g. `2002-01-25T07:00:00+02:00`);
+//! - a date given as `YYYY/MM/DD`, `YYYY-MM-DD`, `MM/DD/YYYY` or
+//!   `MM-DD-YYYY`;
+//! - the number of seconds since the epoch.
+//!
+//! These are parsed when the configuration is loaded so that mistakes are
+//! reported straight away instead of when duplicity is run.
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use error::Error;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Formats of dates accepted by duplicity.
+const DATE_FORMATS: &[&str] = &["%Y/%m/%d", "%Y-%m-%d", "%m/%d/%Y", "%m-%d-%Y"];
+
+/// Unit of an interval, from largest to smallest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Unit {
+    Year,
+    Month,
+    Week,
+    Day,
+    Hour,
+    Minute,
+    Second,
+}
+
+impl Unit {
+    fn from_char(c: char) -> Option<Unit> {
+        match c {
+            'Y' => Some(Unit::Year),
+            'M' => Some(Unit::Month),
+            'W' => Some(Unit::Week),
+            'D' => Some(Unit::Day),
+            'h' => Some(Unit::Hour),
+            'm' => Some(Unit::Minute),
+            's' => Some(Unit::Second),
+            _ => None,
+        }
+    }
+
+    fn as_char(self) -> char {
+        match self {
+            Unit::Year => 'Y',
+            Unit::Month => 'M',
+            Unit::Week => 'W',
+            Unit::Day => 'D',
+            Unit::Hour => 'h',
+            Unit::Minute => 'm',
+            Unit::Second => 's',
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Year => "year",
+            Unit::Month => "month",
+            Unit::Week => "week",
+            Unit::Day => "day",
+            Unit::Hour => "hour",
+            Unit::Minute => "minute",
+            Unit::Second => "second",
+        }
+    }
+}
+
+/// An interval such as `1D2h`.
+///
+/// The amounts of the same unit are added together, so that `1h1D1h` and
+/// `1D2h` are the same interval.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DuplicityInterval(BTreeMap<Unit, u64>);
+
+impl DuplicityInterval {
+    pub fn is_zero(&self) -> bool {
+        self.0.values().all(|&n| n == 0)
+    }
+
+    /// Describe the interval in words (e.g. `1 day and 2 hours`).
+    pub fn describe(&self) -> String {
+        let parts: Vec<_> = self
+            .0
+            .iter()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(unit, &n)| format!("{} {}{}", n, unit.name(), if n == 1 { "" } else { "s" }))
+            .collect();
+        match parts.split_last() {
+            None => "0 seconds".to_owned(),
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+        }
+    }
+}
+
+impl FromStr for DuplicityInterval {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || {
+            Error::new(format!(
+                "Invalid interval '{}'; it must be made of integers each followed by one of the \
+                 units s, m, h, D, W, M or Y (e.g. '1D2h').",
+                s
+            ))
+        };
+
+        let mut interval = BTreeMap::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let n: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            let unit = rest[digits..]
+                .chars()
+                .next()
+                .and_then(Unit::from_char)
+                .ok_or_else(invalid)?;
+            *interval.entry(unit).or_insert(0) += n;
+            rest = &rest[digits + 1..];
+        }
+
+        if interval.is_empty() {
+            Err(invalid())
+        } else {
+            Ok(DuplicityInterval(interval))
+        }
+    }
+}
+
+impl fmt::Display for DuplicityInterval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0s");
+        }
+        for (unit, &n) in self.0.iter().filter(|&(_, &n)| n > 0) {
+            write!(f, "{}{}", n, unit.as_char())?;
+        }
+        Ok(())
+    }
+}
+
+/// A time in any of the formats accepted by duplicity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DuplicityTime {
+    Now,
+    /// Interval before now.
+    Interval(DuplicityInterval),
+    DateTime(DateTime<FixedOffset>),
+    /// Date and time without a timezone, which duplicity takes as local.
+    LocalDateTime(NaiveDateTime),
+    Date(NaiveDate),
+    /// Seconds since the epoch.
+    Epoch(i64),
+}
+
+impl DuplicityTime {
+    /// Check whether the time is now, either explicitly or as an empty
+    /// interval.
+    pub fn is_now(&self) -> bool {
+        match self {
+            DuplicityTime::Now => true,
+            DuplicityTime::Interval(interval) => interval.is_zero(),
+            _ => false,
+        }
+    }
+
+    /// Describe the time in words.
+    pub fn describe(&self) -> String {
+        match self {
+            DuplicityTime::Now => "now".to_owned(),
+            DuplicityTime::Interval(interval) => format!("{} ago", interval.describe()),
+            DuplicityTime::DateTime(time) => time.format("%-d %B %Y, %H:%M:%S %:z").to_string(),
+            DuplicityTime::LocalDateTime(time) => {
+                time.format("%-d %B %Y, %H:%M:%S local time").to_string()
+            }
+            DuplicityTime::Date(date) => date.format("%-d %B %Y").to_string(),
+            DuplicityTime::Epoch(seconds) => match Utc.timestamp_opt(*seconds, 0).single() {
+                Some(time) => time.format("%-d %B %Y, %H:%M:%S UTC").to_string(),
+                None => format!("{} seconds since the epoch", seconds),
+            },
+        }
+    }
+}
+
+impl FromStr for DuplicityTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        if s == "now" {
+            return Ok(DuplicityTime::Now);
+        }
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            return s
+                .parse()
+                .map(DuplicityTime::Epoch)
+                .map_err(|_| Error::new(format!("Time '{}' is out of range.", s)));
+        }
+        if s.starts_with(|c: char| c.is_ascii_digit())
+            && s.ends_with(|c: char| Unit::from_char(c).is_some())
+        {
+            return s.parse().map(DuplicityTime::Interval);
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(DuplicityTime::DateTime(time));
+        }
+        if let Ok(time) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S") {
+            return Ok(DuplicityTime::LocalDateTime(time));
+        }
+        DATE_FORMATS
+            .iter()
+            .filter_map(|format| NaiveDate::parse_from_str(s, format).ok())
+            .next()
+            .map(DuplicityTime::Date)
+            .ok_or_else(|| {
+                Error::new(format!(
+                    "Invalid time '{}'; it must be 'now', an interval (e.g. '1D2h'), a date \
+                     (e.g. '2002-01-25' or '2002-01-25T07:00:00+02:00') or a number of seconds \
+                     since the epoch.",
+                    s
+                ))
+            })
+    }
+}
+
+impl fmt::Display for DuplicityTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DuplicityTime::Now => write!(f, "now"),
+            DuplicityTime::Interval(interval) => interval.fmt(f),
+            DuplicityTime::DateTime(time) => write!(f, "{}", time.to_rfc3339()),
+            DuplicityTime::LocalDateTime(time) => write!(f, "{}", time.format("%Y-%m-%dT%H:%M:%S")),
+            DuplicityTime::Date(date) => write!(f, "{}", date.format("%Y-%m-%d")),
+            DuplicityTime::Epoch(seconds) => write!(f, "{}", seconds),
+        }
+    }
+}
+
+impl Serialize for DuplicityTime {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            DuplicityTime::Epoch(seconds) => serializer.serialize_i64(*seconds),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+
+/// Visitor accepting times as strings, or as integers for the seconds since
+/// the epoch.
+struct DuplicityTimeVisitor;
+
+impl<'de> Visitor<'de> for DuplicityTimeVisitor {
+    type Value = DuplicityTime;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a time in one of the formats accepted by duplicity")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<DuplicityTime, E>
+    where
+        E: de::Error,
+    {
+        s.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E>(self, seconds: i64) -> Result<DuplicityTime, E>
+    where
+        E: de::Error,
+    {
+        Ok(DuplicityTime::Epoch(seconds))
+    }
+
+    fn visit_u64<E>(self, seconds: u64) -> Result<DuplicityTime, E>
+    where
+        E: de::Error,
+    {
+        self.visit_str(&seconds.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for DuplicityTime {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(DuplicityTimeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DuplicityTime;
+
+    #[test]
+    fn parse() {
+        let canonical = |s: &str| s.parse::<DuplicityTime>().map(|t| t.to_string());
+
+        assert_eq!(canonical("now").unwrap(), "now");
+        assert_eq!(canonical("2h1D").unwrap(), "1D2h");
+        assert_eq!(canonical("1h1D1h").unwrap(), "1D2h");
+        assert_eq!(canonical("0D").unwrap(), "0s");
+        assert_eq!(
+            canonical("2002-01-25T07:00:00+02:00").unwrap(),
+            "2002-01-25T07:00:00+02:00"
+        );
+        assert_eq!(
+            canonical("2002-01-25T07:00:00").unwrap(),
+            "2002-01-25T07:00:00"
+        );
+        assert_eq!(canonical("2002/01/25").unwrap(), "2002-01-25");
+        assert_eq!(canonical("01-25-2002").unwrap(), "2002-01-25");
+        assert_eq!(canonical("1011970800").unwrap(), "1011970800");
+        assert!(canonical("1D2x").is_err());
+        assert!(canonical("D").is_err());
+        assert!(canonical("2002-13-25").is_err());
+        assert!(canonical("yesterday").is_err());
+
+        assert!("0D0h".parse::<DuplicityTime>().unwrap().is_now());
+        assert_eq!(
+            "1D2h".parse::<DuplicityTime>().unwrap().describe(),
+            "1 day and 2 hours ago"
+        );
+        assert_eq!(
+            "1Y1W3m".parse::<DuplicityTime>().unwrap().describe(),
+            "1 year, 1 week and 3 minutes ago"
+        );
+        assert_eq!(
+            "1011970800".parse::<DuplicityTime>().unwrap().describe(),
+            "25 January 2002, 15:00:00 UTC"
+        );
+    }
+}